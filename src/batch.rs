@@ -0,0 +1,392 @@
+//! Batch of stack usage queries answered in a single run
+//!
+//! Building the firmware and constructing the call graph is the expensive part of the analysis.
+//! A query file lets CI ask about several entry points (e.g. every interrupt handler) without
+//! paying that cost once per entry point. The answers, and the durations of the phases of the
+//! analysis, are printed to stdout in JSON format.
+//!
+//! The query file contains `key = value` lines. Each `root` line starts a new query; the `budget`
+//! and `exclude` lines that follow it apply to that query. Empty lines and lines that start with
+//! `#` are ignored.
+//!
+//! ``` text
+//! # interrupt handlers
+//! root = USART1
+//! budget = 512
+//! exclude = core::panicking::*
+//!
+//! root = main
+//! ```
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail};
+use petgraph::graph::{Graph, NodeIndex};
+
+use crate::{fallback, intrinsics::matches_symbol, FindError, Max, Node, Timings};
+
+/// A stack usage query
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    /// Name of the function whose worst-case stack usage will be computed
+    pub root: String,
+    /// If set, the query fails when the worst-case stack usage is not known to be below this
+    /// number of bytes
+    pub budget: Option<u64>,
+    /// Calls into functions that match these patterns are not followed
+    pub excludes: Vec<String>,
+}
+
+/// Parses the contents of a query file
+pub fn parse_queries(contents: &str) -> anyhow::Result<Vec<Query>> {
+    let mut queries = vec![];
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        let lineno = i + 1;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().expect("UNREACHABLE").trim();
+        let value = match parts.next() {
+            Some(value) if !value.trim().is_empty() => value.trim(),
+            _ => bail!("line {}: expected `<key> = <value>`", lineno),
+        };
+
+        if key == "root" {
+            queries.push(Query {
+                root: value.to_owned(),
+                budget: None,
+                excludes: vec![],
+            });
+            continue;
+        }
+
+        let query = queries
+            .last_mut()
+            .ok_or_else(|| anyhow!("line {}: `{}` must come after a `root` line", lineno, key))?;
+        match key {
+            "budget" => {
+                query.budget =
+                    Some(value.parse().map_err(|e| {
+                        anyhow!("line {}: invalid budget `{}`: {}", lineno, value, e)
+                    })?);
+            }
+
+            "exclude" => query.excludes.push(value.to_owned()),
+
+            _ => bail!("line {}: unknown key `{}`", lineno, key),
+        }
+    }
+
+    Ok(queries)
+}
+
+/// Loads the queries listed in the file at `path`
+///
+/// See `parse_queries` for the format of the file
+pub fn load_queries(path: impl AsRef<Path>) -> anyhow::Result<Vec<Query>> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)
+        .map_err(|e| anyhow!("couldn't read `{}`: {}", path.display(), e))?;
+    parse_queries(&contents).map_err(|e| anyhow!("{}: {}", path.display(), e))
+}
+
+struct Answer<'q> {
+    query: &'q Query,
+    // maximum stack usage and maximum stack usage of the fast path
+    result: Result<(Max, Max), &'static str>,
+}
+
+impl Answer<'_> {
+    fn ok(&self) -> bool {
+        match (self.result, self.query.budget) {
+            (Err(_), _) => false,
+            (Ok(_), None) => true,
+            (Ok((Max::Exact(n), _)), Some(budget)) => n <= budget,
+            // can't prove that the budget is respected
+            (Ok((Max::LowerBound(_), _)), Some(_)) => false,
+        }
+    }
+}
+
+/// Answers the `queries` and prints the answers to stdout; returns the exit code
+pub(crate) fn run(
+    g: &Graph<Node, ()>,
+    queries: &[Query],
+    timings: &mut Timings,
+) -> anyhow::Result<i32> {
+    let answers = queries
+        .iter()
+        .map(|query| Answer {
+            query,
+            result: answer(g, query),
+        })
+        .collect::<Vec<_>>();
+    timings.lap("solve");
+
+    // NOTE the JSON output can't include the time it takes to render itself
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    render(&mut stdout, &answers, timings)?;
+    timings.lap("render");
+
+    Ok(if answers.iter().all(Answer::ok) { 0 } else { 1 })
+}
+
+fn answer(g: &Graph<Node, ()>, query: &Query) -> Result<(Max, Max), &'static str> {
+    let root = crate::find_node(g, &query.root).map_err(|e| match e {
+        FindError::NotFound => "root not found",
+        FindError::Ambiguous => "root is ambiguous",
+    })?;
+
+    let excluded = g
+        .node_indices()
+        .filter(|idx| {
+            query
+                .excludes
+                .iter()
+                .any(|pattern| matches_symbol(pattern, &g[*idx].name))
+        })
+        .collect::<HashSet<_>>();
+
+    if excluded.contains(&root) {
+        return Err("root is excluded");
+    }
+
+    let mut g = crate::filter(g, root, &excluded);
+    fallback::solve_fast_path(&mut g);
+    crate::solve(&mut g);
+
+    // `filter` puts the root node first
+    let root = &g[NodeIndex::new(0)];
+    let max = root.max.expect("UNREACHABLE");
+    Ok((max, root.fast_max.unwrap_or(max)))
+}
+
+fn render(w: &mut impl Write, answers: &[Answer], timings: &Timings) -> io::Result<()> {
+    write!(w, "{{\"queries\":[")?;
+    for (i, answer) in answers.iter().enumerate() {
+        if i != 0 {
+            write!(w, ",")?;
+        }
+
+        write!(w, "{{\"root\":")?;
+        write_str(w, &answer.query.root)?;
+
+        match answer.result {
+            Ok((max, fast_max)) => {
+                let (n, exact) = match max {
+                    Max::Exact(n) => (n, true),
+                    Max::LowerBound(n) => (n, false),
+                };
+                let (Max::Exact(fast_n) | Max::LowerBound(fast_n)) = fast_max;
+                write!(
+                    w,
+                    ",\"max\":{},\"exact\":{},\"fast_max\":{}",
+                    n, exact, fast_n
+                )?;
+            }
+
+            Err(e) => {
+                write!(
+                    w,
+                    ",\"max\":null,\"exact\":false,\"fast_max\":null,\"error\":"
+                )?;
+                write_str(w, e)?;
+            }
+        }
+
+        if let Some(budget) = answer.query.budget {
+            write!(w, ",\"budget\":{}", budget)?;
+        } else {
+            write!(w, ",\"budget\":null")?;
+        }
+
+        write!(w, ",\"ok\":{}}}", answer.ok())?;
+    }
+    write!(w, "],\"timings\":")?;
+    timings.json(w)?;
+    writeln!(w, "}}")
+}
+
+// writes `s` as a JSON string
+pub(crate) fn write_str(w: &mut impl Write, s: &str) -> io::Result<()> {
+    write!(w, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(w, "\\\"")?,
+            '\\' => write!(w, "\\\\")?,
+            '\n' => write!(w, "\\n")?,
+            c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+            c => write!(w, "{}", c)?,
+        }
+    }
+    write!(w, "\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use petgraph::graph::DiGraph;
+
+    use super::{Answer, Query};
+    use crate::{Max, Node, Timings};
+
+    #[test]
+    fn parse() {
+        let queries = super::parse_queries(
+            "# handlers
+             root = USART1
+             budget = 512
+             exclude = core::panicking::*
+             exclude = foo
+
+             root = main
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            queries,
+            [
+                Query {
+                    root: "USART1".to_owned(),
+                    budget: Some(512),
+                    excludes: vec!["core::panicking::*".to_owned(), "foo".to_owned()],
+                },
+                Query {
+                    root: "main".to_owned(),
+                    budget: None,
+                    excludes: vec![],
+                },
+            ]
+        );
+
+        assert!(super::parse_queries("budget = 8").is_err());
+        assert!(super::parse_queries("root =").is_err());
+        assert!(super::parse_queries("root = main\nbudget = -1").is_err());
+        assert!(super::parse_queries("root = main\nfoo = bar").is_err());
+    }
+
+    #[test]
+    fn answer() {
+        let mut g = DiGraph::new();
+        let main = g.add_node(Node("main", Some(8), false));
+        let foo = g.add_node(Node("foo", Some(16), false));
+        let bar = g.add_node(Node("bar", Some(1024), false));
+        let baz = g.add_node(Node("baz", None, false));
+        let panic = g.add_node(Node("core::panicking::panic", Some(2048), false));
+        g.add_edge(main, foo, ());
+        g.add_edge(foo, bar, ());
+        g.add_edge(main, baz, ());
+        g.add_edge(foo, panic, ());
+        // two instances of the same function
+        g.add_node(Node("_ZN3app4init17h0123456789abcdefE", Some(8), false));
+        g.add_node(Node("_ZN3app4init17hfedcba9876543210E", Some(8), false));
+
+        let query = |root: &str, excludes: &[&str]| Query {
+            root: root.to_owned(),
+            budget: None,
+            excludes: excludes.iter().map(|s| s.to_string()).collect(),
+        };
+
+        let answer = |root, excludes| super::answer(&g, &query(root, excludes));
+
+        assert!(answer("foo", &[]) == Ok((Max::Exact(2064), Max::Exact(1040))));
+        assert!(answer("main", &[]) == Ok((Max::LowerBound(2072), Max::LowerBound(1048))));
+        assert!(answer("main", &["bar", "ba*", "core::*"]) == Ok((Max::Exact(24), Max::Exact(24))));
+        assert!(answer("qux", &[]) == Err("root not found"));
+        assert!(answer("app::init", &[]) == Err("root is ambiguous"));
+        assert!(answer("main", &["m*"]).is_err());
+    }
+
+    #[test]
+    fn duplicates() {
+        // two functions that share a name but not their stack usage
+        let mut g = DiGraph::new();
+        let main = g.add_node(Node("main", Some(8), false));
+        let helper1 = g.add_node(Node("helper", Some(16), false));
+        let helper2 = g.add_node(Node("helper", Some(256), false));
+        g[helper1].address = Some(0x0800_0100);
+        g[helper2].address = Some(0x0800_0200);
+        g.add_edge(main, helper1, ());
+        g.add_edge(main, helper2, ());
+
+        let answer = |root: &str| {
+            super::answer(
+                &g,
+                &Query {
+                    root: root.to_owned(),
+                    budget: None,
+                    excludes: vec![],
+                },
+            )
+        };
+
+        assert!(answer("helper @ 0x08000100") == Ok((Max::Exact(16), Max::Exact(16))));
+        assert!(answer("helper @ 0x08000200") == Ok((Max::Exact(256), Max::Exact(256))));
+        assert!(answer("main") == Ok((Max::Exact(264), Max::Exact(264))));
+        assert!(answer("helper") == Err("root is ambiguous"));
+        assert!(answer("helper @ 0x08000300") == Err("root not found"));
+        assert!(answer("helper @ 256") == Err("root not found"));
+    }
+
+    #[test]
+    fn render() {
+        let query = |root: &str, budget| Query {
+            root: root.to_owned(),
+            budget,
+            excludes: vec![],
+        };
+        let queries = [
+            query("main", Some(2048)),
+            query("<\"USART1\">", Some(8)),
+            query("foo", None),
+            query("bar", None),
+        ];
+        let answers = [
+            Answer {
+                query: &queries[0],
+                result: Ok((Max::Exact(1024), Max::Exact(512))),
+            },
+            Answer {
+                query: &queries[1],
+                result: Ok((Max::LowerBound(0), Max::LowerBound(0))),
+            },
+            Answer {
+                query: &queries[2],
+                result: Ok((Max::LowerBound(16), Max::LowerBound(16))),
+            },
+            Answer {
+                query: &queries[3],
+                result: Err("root not found"),
+            },
+        ];
+
+        let mut timings = Timings::new();
+        timings.record("ir parse", Duration::from_micros(1234));
+
+        let mut out = vec![];
+        super::render(&mut out, &answers, &timings).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"queries\":[\
+             {\"root\":\"main\",\"max\":1024,\"exact\":true,\"fast_max\":512,\"budget\":2048,\"ok\":true},\
+             {\"root\":\"<\\\"USART1\\\">\",\"max\":0,\"exact\":false,\"fast_max\":0,\"budget\":8,\"ok\":false},\
+             {\"root\":\"foo\",\"max\":16,\"exact\":false,\"fast_max\":16,\"budget\":null,\"ok\":true},\
+             {\"root\":\"bar\",\"max\":null,\"exact\":false,\"fast_max\":null,\"error\":\"root not found\",\
+             \"budget\":null,\"ok\":false}\
+             ],\"timings\":{\"ir parse\":1234}}\n"
+        );
+    }
+}
@@ -5,7 +5,9 @@
 use std::{process, path::PathBuf};
 use clap::Parser;
 
-use cargo_call_stack::OutputFormat;
+use cargo_call_stack::{
+    Intrinsics, Options, OutputFormat, StackLimits, Timings, Units, Unreachable,
+};
 use env_logger::{Builder, Env};
 
 /// Generate a call graph and perform whole program stack usage analysis
@@ -16,10 +18,105 @@ struct Args {
     #[arg(long, value_name = "TRIPLE")]
     target: Option<String>,
 
+    /// Print the duration of each phase of the analysis
+    #[arg(short, long)]
+    verbose: bool,
+
     /// Output format
     #[arg(long, default_value = "dot")]
     format: OutputFormat,
 
+    /// Units used to render byte figures in the `dot` and `top` formats
+    #[arg(long, default_value = "bytes")]
+    units: Units,
+
+    /// File with hand-written stack models (`<pattern> = <stack>` lines)
+    #[arg(long, value_name = "FILE")]
+    intrinsics: Option<PathBuf>,
+
+    /// (ARMv8-M) check that the worst-case stack usage fits above this MSPLIM value
+    #[arg(
+        long,
+        value_name = "ADDR",
+        value_parser = cargo_call_stack::parse_address,
+        requires = "main_root"
+    )]
+    msplim: Option<u32>,
+
+    /// (ARMv8-M) check that the worst-case stack usage fits above this PSPLIM value
+    #[arg(
+        long,
+        value_name = "ADDR",
+        value_parser = cargo_call_stack::parse_address,
+        requires_all = ["psp_top", "thread"]
+    )]
+    psplim: Option<u32>,
+
+    /// (ARMv8-M) initial value of the process stack pointer
+    #[arg(long, value_name = "ADDR", value_parser = cargo_call_stack::parse_address)]
+    psp_top: Option<u32>,
+
+    /// (ARMv8-M) write the recommended MSPLIM / PSPLIM values, as Rust constants, to this file
+    #[arg(long, value_name = "FILE")]
+    gen_stack_limits: Option<PathBuf>,
+
+    /// Stack space, in bytes, to leave between the worst-case stack usage and the generated limits
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 0,
+        requires = "gen_stack_limits"
+    )]
+    stack_limit_margin: u32,
+
+    /// (ARMv8-M) entry point of the code that runs on the main stack in thread mode, e.g. `main`
+    #[arg(long, value_name = "SYMBOL")]
+    main_root: Option<String>,
+
+    /// (ARMv8-M) exception handler; handlers run on the main stack and can preempt the main root
+    /// and each other (can be repeated)
+    #[arg(long, value_name = "SYMBOL", requires = "main_root")]
+    handler: Vec<String>,
+
+    /// (ARMv8-M) entry point of a thread that runs on the process stack (can be repeated)
+    #[arg(long, value_name = "SYMBOL")]
+    thread: Vec<String>,
+
+    /// Highlight the differences with this call graph, previously produced in the `dot` format
+    #[arg(long, value_name = "DOT_FILE")]
+    baseline: Option<PathBuf>,
+
+    /// Answer the stack usage queries in this file and print the answers in JSON format
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "baseline",
+            "format",
+            "units",
+            "msplim",
+            "psplim",
+            "gen_stack_limits",
+            "main_root",
+            "handler",
+            "thread",
+        ]
+    )]
+    queries: Option<PathBuf>,
+
+    /// Remove the call sites from CALLER to CALLEE, asserting they are unreachable (can be repeated)
+    #[arg(
+        long,
+        value_name = "CALLER -> CALLEE",
+        value_parser = cargo_call_stack::parse_unreachable
+    )]
+    unreachable: Vec<Unreachable>,
+
+    /// Write anonymized statistics about the analysis (graph size, resolution methods, timings,
+    /// etc.), in JSON format, to this file
+    #[arg(long, value_name = "FILE")]
+    stats: Option<PathBuf>,
+
     /// Path to the elf file
     #[arg(long, value_name = "ELF_PATH")]
     elf: Option<String>,
@@ -47,11 +144,52 @@ fn run() -> anyhow::Result<i32> {
     Builder::from_env(Env::default().default_filter_or("warn")).init();
     let args = Args::parse();
 
+    let mut intrinsics = Intrinsics::new();
+    if let Some(path) = &args.intrinsics {
+        intrinsics.load(path)?;
+    }
+
+    let queries = if let Some(path) = &args.queries {
+        cargo_call_stack::load_queries(path)?
+    } else {
+        vec![]
+    };
+
     let path = PathBuf::from(args.elf.unwrap());
     let compiler_builtins_rlib_path = args.compiler_builtins_rlib_path.unwrap();
     let compiler_builtins_ll_path = args.compiler_builtins_ll_path.unwrap();
     let target = args.target.unwrap();
     let prefix = String::new();
 
-    cargo_call_stack::analyze(path, compiler_builtins_rlib_path, compiler_builtins_ll_path, &target, prefix, None, args.format)
+    let options = Options {
+        start: None,
+        format: args.format,
+        units: args.units,
+        intrinsics,
+        stack_limits: StackLimits {
+            msplim: args.msplim,
+            psplim: args.psplim,
+            psp_top: args.psp_top,
+            generate: args.gen_stack_limits,
+            margin: args.stack_limit_margin,
+            main: args.main_root,
+            handlers: args.handler,
+            threads: args.thread,
+        },
+        baseline: args.baseline,
+        queries,
+        unreachable: args.unreachable,
+        stats: args.stats,
+        verbose: args.verbose,
+        timings: Timings::new(),
+    };
+
+    cargo_call_stack::analyze(
+        path,
+        compiler_builtins_rlib_path,
+        compiler_builtins_ll_path,
+        &target,
+        prefix,
+        options,
+    )
 }
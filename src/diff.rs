@@ -0,0 +1,362 @@
+//! Comparison of a call graph against a baseline call graph
+//!
+//! The baseline is a call graph previously produced by this tool in the `dot` format. Nodes are
+//! matched by name, plus address when the name is not unique, and `?` nodes are matched by their
+//! caller. In the rendered graph added nodes and edges are green, removed ones are red and changed
+//! stack usage values are annotated with their baseline value.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{anyhow, bail};
+use petgraph::graph::Graph;
+
+use crate::{Local, Max, Node};
+
+pub(crate) const ADDED: &str = "green";
+pub(crate) const REMOVED: &str = "red";
+
+/// Identity of a node across call graphs
+///
+/// Nodes are identified by their label, which includes the address of the symbols that share a
+/// name. `?` nodes (calls into unknown functions) all have the same label but a single caller so
+/// they are also identified by the label of their caller.
+pub(crate) type Key = (String, Option<String>);
+
+/// A node of the baseline call graph
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct OldNode {
+    pub(crate) max: Option<Max>,
+    pub(crate) local: Local,
+    pub(crate) dashed: bool,
+}
+
+/// A call graph parsed from the `dot` output of this tool
+pub(crate) struct Baseline {
+    pub(crate) nodes: BTreeMap<Key, OldNode>,
+    pub(crate) edges: BTreeSet<(Key, Key)>,
+}
+
+impl Baseline {
+    pub(crate) fn parse(dot: &str) -> anyhow::Result<Self> {
+        // `dot` node identifier -> position in `labels`
+        let mut ids = BTreeMap::new();
+        let mut labels = vec![];
+        let mut nodes = vec![];
+        let mut edges = vec![];
+
+        for (i, line) in dot.lines().enumerate() {
+            let line = line.trim();
+            let lineno = i + 1;
+
+            if let Some(label_start) = line.find(" [label=\"") {
+                let id = &line[..label_start];
+                let rest = &line[label_start + " [label=\"".len()..];
+                let label =
+                    unquote(rest).ok_or_else(|| anyhow!("line {}: unterminated label", lineno))?;
+                let attrs = &rest[label.len() + 1..];
+
+                let mut parts = label.split("\\n");
+                let name = parts.next().expect("UNREACHABLE").replace("\\\"", "\"");
+                let mut max = None;
+                let mut local = Local::Unknown;
+                for part in parts {
+                    if let Some(value) = part.strip_prefix("max ") {
+                        max =
+                            Some(parse_max(value).map_err(|e| anyhow!("line {}: {}", lineno, e))?);
+                    } else if let Some(value) = part.strip_prefix("local = ") {
+                        if value != "?" {
+                            local = Local::Exact(
+                                parse_bytes(value)
+                                    .map_err(|e| anyhow!("line {}: {}", lineno, e))?,
+                            );
+                        }
+                    }
+                }
+
+                let dashed = attrs
+                    .split(|c: char| c.is_whitespace() || c == ']')
+                    .any(|attr| attr == "style=dashed");
+
+                ids.insert(id, labels.len());
+                labels.push(name);
+                nodes.push(OldNode { max, local, dashed });
+            } else if let Some((from, to)) = split_edge(line) {
+                edges.push((from, to, lineno));
+            }
+        }
+
+        let edges = edges
+            .into_iter()
+            .map(|(from, to, lineno)| match (ids.get(from), ids.get(to)) {
+                (Some(from), Some(to)) => Ok((*from, *to)),
+                _ => Err(anyhow!("line {}: edge between unknown nodes", lineno)),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let keys = keys(labels, &edges);
+        Ok(Baseline {
+            edges: edges
+                .iter()
+                .map(|(from, to)| (keys[*from].clone(), keys[*to].clone()))
+                .collect(),
+            nodes: keys.into_iter().zip(nodes).collect(),
+        })
+    }
+}
+
+/// Returns the key of each node of `g`, in node index order
+pub(crate) fn graph_keys(g: &Graph<Node, ()>) -> Vec<Key> {
+    let labels = g.node_weights().map(Node::label).collect();
+    let edges = g
+        .raw_edges()
+        .iter()
+        .map(|edge| (edge.source().index(), edge.target().index()))
+        .collect::<Vec<_>>();
+
+    keys(labels, &edges)
+}
+
+fn keys(labels: Vec<String>, edges: &[(usize, usize)]) -> Vec<Key> {
+    let mut callers = vec![None; labels.len()];
+    for (from, to) in edges {
+        callers[*to].get_or_insert(*from);
+    }
+
+    labels
+        .iter()
+        .zip(callers)
+        .map(|(label, caller)| {
+            let caller = if label == "?" {
+                caller.map(|caller| labels[caller].clone())
+            } else {
+                None
+            };
+
+            (label.clone(), caller)
+        })
+        .collect()
+}
+
+// returns the contents of a string literal whose opening quote has already been consumed
+fn unquote(s: &str) -> Option<&str> {
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return Some(&s[..i]),
+            _ => escaped = false,
+        }
+    }
+
+    None
+}
+
+// `1 -> 2`
+fn split_edge(line: &str) -> Option<(&str, &str)> {
+    let mut parts = line.splitn(2, " -> ");
+    let from = parts.next()?;
+    let to = parts.next()?.split_whitespace().next()?;
+
+    if from.bytes().all(|b| b.is_ascii_digit()) && to.bytes().all(|b| b.is_ascii_digit()) {
+        Some((from, to))
+    } else {
+        None
+    }
+}
+
+// `= 1,234` or `>= 1,234`
+fn parse_max(s: &str) -> anyhow::Result<Max> {
+    if let Some(n) = s.strip_prefix(">= ") {
+        Ok(Max::LowerBound(parse_bytes(n)?))
+    } else if let Some(n) = s.strip_prefix("= ") {
+        Ok(Max::Exact(parse_bytes(n)?))
+    } else {
+        bail!("invalid max stack usage `{}`", s)
+    }
+}
+
+fn parse_bytes(s: &str) -> anyhow::Result<u64> {
+    if s.ends_with("KiB") {
+        bail!("KiB figures are lossy; produce the baseline using `--units bytes`")
+    }
+
+    let digits = s.trim_end_matches(" B").replace(',', "");
+    digits
+        .parse()
+        .map_err(|e| anyhow!("invalid stack usage `{}`: {}", s, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use petgraph::graph::DiGraph;
+
+    use super::{Baseline, Key, OldNode};
+    use crate::{Local, Max, Node, Units};
+
+    const BASELINE: &str = r#"digraph {
+    node [fontname=monospace shape=box]
+    0 [label="main\nmax = 1,032\nlocal = 8"]
+    1 [label="foo\nmax = 1,024\nlocal = 1,024"]
+    2 [label="<\"bar\">\nmax >= 16\nlocal = ?" style=dashed]
+    0 -> 1
+    1 -> 2
+
+    subgraph cluster_0 {
+        style=dashed
+        fontname=monospace
+        label="SCC0"
+        2
+    }
+}
+"#;
+
+    fn key(label: &str) -> Key {
+        (label.to_owned(), None)
+    }
+
+    fn diff(g: &DiGraph<Node, ()>, baseline: &Baseline) -> String {
+        let mut out = vec![];
+        crate::render_dot(&mut out, g, &[], Some(baseline), Units::Bytes).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let baseline = Baseline::parse(BASELINE).unwrap();
+
+        assert_eq!(baseline.nodes.len(), 3);
+        assert!(
+            baseline.nodes[&key("main")]
+                == OldNode {
+                    max: Some(Max::Exact(1032)),
+                    local: Local::Exact(8),
+                    dashed: false,
+                }
+        );
+        assert!(
+            baseline.nodes[&key("<\"bar\">")]
+                == OldNode {
+                    max: Some(Max::LowerBound(16)),
+                    local: Local::Unknown,
+                    dashed: true,
+                }
+        );
+        assert_eq!(baseline.edges.len(), 2);
+        assert!(baseline.edges.contains(&(key("foo"), key("<\"bar\">"))));
+
+        assert!(Baseline::parse("    0 [label=\"main\\nmax = 1.5 KiB\\nlocal = 8\"]").is_err());
+        assert!(
+            Baseline::parse("    0 [label=\"main\\nmax = 8\\nlocal = 8\"]\n    0 -> 1").is_err()
+        );
+    }
+
+    #[test]
+    fn render() {
+        let baseline = Baseline::parse(BASELINE).unwrap();
+
+        let mut g = DiGraph::new();
+        let main = g.add_node(Node("main", Some(8), false));
+        let foo = g.add_node(Node("foo", Some(2048), false));
+        let baz = g.add_node(Node("baz", Some(0), false));
+        g[main].max = Some(Max::Exact(2056));
+        g[foo].max = Some(Max::Exact(2048));
+        g[baz].max = Some(Max::Exact(0));
+        g.add_edge(main, foo, ());
+        g.add_edge(foo, baz, ());
+
+        assert_eq!(
+            diff(&g, &baseline),
+            r#"digraph {
+    node [fontname=monospace shape=box]
+    0 [label="main\nmax = 2,056 (was 1,032)\nlocal = 8"]
+    1 [label="foo\nmax = 2,048 (was 1,024)\nlocal = 2,048 (was 1,024)"]
+    2 [label="baz\nmax = 0\nlocal = 0" color=green fontcolor=green]
+    3 [label="<\"bar\">\nmax >= 16\nlocal = ?" style=dashed color=red fontcolor=red]
+    0 -> 1
+    1 -> 2 [color=green]
+    1 -> 3 [color=red]
+}
+"#
+        );
+    }
+
+    #[test]
+    fn duplicates() {
+        let baseline = Baseline::parse(
+            r#"digraph {
+    0 [label="main\nmax = 16\nlocal = 8"]
+    1 [label="helper @ 0x08000100\nmax = 8\nlocal = 8"]
+    0 -> 1
+}
+"#,
+        )
+        .unwrap();
+
+        let mut g = DiGraph::new();
+        let main = g.add_node(Node("main", Some(8), false));
+        let helper1 = g.add_node(Node("helper", Some(8), false));
+        let helper2 = g.add_node(Node("helper", Some(8), false));
+        g[helper1].address = Some(0x0800_0100);
+        g[helper2].address = Some(0x0800_0200);
+        g.add_edge(main, helper1, ());
+        g.add_edge(main, helper2, ());
+
+        assert_eq!(
+            diff(&g, &baseline),
+            r#"digraph {
+    node [fontname=monospace shape=box]
+    0 [label="main\nlocal = 8"]
+    1 [label="helper @ 0x08000100\nlocal = 8"]
+    2 [label="helper @ 0x08000200\nlocal = 8" color=green fontcolor=green]
+    0 -> 1
+    0 -> 2 [color=green]
+}
+"#
+        );
+    }
+
+    #[test]
+    fn unknown() {
+        // `?` nodes are told apart by their caller
+        let baseline = Baseline::parse(
+            r#"digraph {
+    0 [label="main\nmax >= 8\nlocal = 8"]
+    1 [label="foo\nmax >= 16\nlocal = 16"]
+    2 [label="?\nlocal = ?"]
+    3 [label="?\nlocal = ?"]
+    0 -> 1
+    0 -> 2
+    1 -> 3
+}
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(baseline.nodes.len(), 4);
+        assert!(baseline
+            .edges
+            .contains(&(key("foo"), ("?".to_owned(), Some("foo".to_owned())))));
+
+        let mut g = DiGraph::new();
+        let main = g.add_node(Node("main", Some(8), false));
+        let foo = g.add_node(Node("foo", Some(16), false));
+        let unknown = g.add_node(Node("?", None, false));
+        g.add_edge(main, foo, ());
+        g.add_edge(foo, unknown, ());
+
+        assert_eq!(
+            diff(&g, &baseline),
+            r#"digraph {
+    node [fontname=monospace shape=box]
+    0 [label="main\nlocal = 8"]
+    1 [label="foo\nlocal = 16"]
+    2 [label="?\nlocal = ?"]
+    3 [label="?\nlocal = ?" color=red fontcolor=red]
+    0 -> 1
+    1 -> 2
+    0 -> 3 [color=red]
+}
+"#
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! Runtime fallbacks and hints that rule them out
+//!
+//! Many functions have a cheap fast path and a heavy fallback that's only taken when something
+//! goes wrong, e.g. `Option::unwrap` on a value that const propagation proved to be `Some`, or an
+//! index that is always in bounds. The fallback (the panic machinery, which usually formats a
+//! message) often dominates the worst-case stack usage. We report the stack usage of the fast path,
+//! i.e. without the calls into fallbacks, next to the worst-case stack usage and let users remove
+//! the call sites that they know to be unreachable.
+
+use std::collections::HashSet;
+
+use log::warn;
+use petgraph::graph::{Graph, NodeIndex};
+
+use crate::{intrinsics::matches_symbol, Node};
+
+// functions that are only called when something goes wrong
+const FALLBACKS: &[&str] = &[
+    "rust_begin_unwind",
+    "core::panicking::*",
+    "core::result::unwrap_failed",
+    "core::option::unwrap_failed",
+    "core::option::expect_failed",
+    "core::slice::index::slice_*",
+    "core::str::slice_error_fail",
+    "core::cell::panic_*",
+    "std::panicking::*",
+    "alloc::alloc::handle_alloc_error",
+];
+
+/// A call site that the user asserts is unreachable
+#[derive(Clone, Debug, PartialEq)]
+pub struct Unreachable {
+    /// Pattern that matches the caller
+    pub caller: String,
+    /// Pattern that matches the callee
+    pub callee: String,
+}
+
+/// Parses a `CALLER -> CALLEE` hint
+pub fn parse_unreachable(s: &str) -> Result<Unreachable, String> {
+    // symbol names may contain ` -> ` themselves, e.g. `foo::<fn() -> u32>`; split at the arrow
+    // that's not nested in brackets
+    let mut depth = 0i32;
+    let mut arrow = None;
+    for (i, c) in s.char_indices() {
+        match c {
+            '<' | '(' | '[' => depth += 1,
+            '>' if s[..i].ends_with(" -") && depth == 0 => {
+                if arrow.is_some() {
+                    return Err(format!("ambiguous hint `{}`: more than one ` -> `", s));
+                }
+
+                arrow = Some(i - 2);
+            }
+            // the arrows of nested function types are not closing brackets
+            '>' if s[..i].ends_with(" -") => {}
+            '>' | ')' | ']' => depth -= 1,
+            _ => {}
+        }
+    }
+
+    let arrow = arrow.ok_or_else(|| format!("expected `CALLER -> CALLEE`, found `{}`", s))?;
+    let caller = s[..arrow].trim();
+    let callee = s[arrow + " -> ".len()..].trim();
+    if caller.is_empty() || callee.is_empty() {
+        return Err(format!("expected `CALLER -> CALLEE`, found `{}`", s));
+    }
+
+    Ok(Unreachable {
+        caller: caller.to_owned(),
+        callee: callee.to_owned(),
+    })
+}
+
+// removes the call sites that the user asserted are unreachable
+pub(crate) fn apply_hints(g: &mut Graph<Node, ()>, hints: &[Unreachable]) {
+    let mut used = vec![false; hints.len()];
+
+    g.retain_edges(|g, edge| {
+        let (caller, callee) = g.edge_endpoints(edge).expect("UNREACHABLE");
+        let (caller, callee) = (&g[caller].name, &g[callee].name);
+
+        let mut keep = true;
+        for (hint, used) in hints.iter().zip(&mut used) {
+            if matches_symbol(&hint.caller, caller) && matches_symbol(&hint.callee, callee) {
+                *used = true;
+                keep = false;
+            }
+        }
+
+        keep
+    });
+
+    for (hint, used) in hints.iter().zip(used) {
+        if !used {
+            warn!(
+                "hint `{} -> {}` doesn't match any call site in the call graph",
+                hint.caller, hint.callee
+            );
+        }
+    }
+}
+
+// computes the maximum stack usage of every node when the calls into fallbacks are not followed
+pub(crate) fn solve_fast_path(g: &mut Graph<Node, ()>) {
+    let fallbacks = g
+        .node_indices()
+        .filter(|idx| {
+            let name = &g[*idx].name;
+            FALLBACKS
+                .iter()
+                .any(|pattern| matches_symbol(pattern, name))
+        })
+        .collect::<HashSet<NodeIndex>>();
+
+    if fallbacks.is_empty() {
+        return;
+    }
+
+    let mut fast = g.clone();
+    fast.retain_edges(|fast, edge| {
+        let (_, callee) = fast.edge_endpoints(edge).expect("UNREACHABLE");
+        !fallbacks.contains(&callee)
+    });
+    crate::solve(&mut fast);
+
+    for idx in g.node_indices() {
+        if !fallbacks.contains(&idx) {
+            g[idx].fast_max = fast[idx].max;
+        }
+    }
+}
+
+// matches `pattern` against both the mangled and the demangled, hash-less `symbol` name
+#[cfg(test)]
+mod tests {
+    use petgraph::graph::DiGraph;
+
+    use super::Unreachable;
+    use crate::{Max, Node};
+
+    #[test]
+    fn parse_unreachable() {
+        let hint = |caller: &str, callee: &str| {
+            Ok(Unreachable {
+                caller: caller.to_owned(),
+                callee: callee.to_owned(),
+            })
+        };
+
+        assert_eq!(
+            super::parse_unreachable("app::main -> core::result::unwrap_failed"),
+            hint("app::main", "core::result::unwrap_failed")
+        );
+        assert_eq!(
+            super::parse_unreachable("app::call::<fn() -> u32> -> core::panicking::*"),
+            hint("app::call::<fn() -> u32>", "core::panicking::*")
+        );
+        assert_eq!(
+            super::parse_unreachable("<app::Foo as core::ops::Fn<(u8,)>>::call -> bar"),
+            hint("<app::Foo as core::ops::Fn<(u8,)>>::call", "bar")
+        );
+
+        assert!(super::parse_unreachable("app::main").is_err());
+        assert!(super::parse_unreachable("app::main -> ").is_err());
+        assert!(super::parse_unreachable("a -> b -> c").is_err());
+    }
+
+    #[test]
+    fn fast_path() {
+        let mut g = DiGraph::new();
+        let main = g.add_node(Node("main", Some(8), false));
+        let foo = g.add_node(Node("foo", Some(16), false));
+        let unwrap_failed = g.add_node(Node(
+            "_ZN4core6result13unwrap_failed17h0123456789abcdefE",
+            Some(64),
+            false,
+        ));
+        let fmt = g.add_node(Node("core::fmt::write", Some(256), false));
+        g.add_edge(main, foo, ());
+        g.add_edge(foo, unwrap_failed, ());
+        g.add_edge(unwrap_failed, fmt, ());
+        g.add_edge(main, fmt, ());
+
+        super::solve_fast_path(&mut g);
+        crate::solve(&mut g);
+
+        assert!(g[main].max == Some(Max::Exact(344)));
+        assert!(g[main].fast_max == Some(Max::Exact(264)));
+        assert!(g[foo].max == Some(Max::Exact(336)));
+        assert!(g[foo].fast_max == Some(Max::Exact(16)));
+        // fallbacks themselves have no fast path
+        assert!(g[unwrap_failed].fast_max.is_none());
+    }
+
+    #[test]
+    fn apply_hints() {
+        let mut g = DiGraph::new();
+        let main = g.add_node(Node("main", Some(8), false));
+        let foo = g.add_node(Node("app::foo", Some(16), false));
+        let panic = g.add_node(Node("core::panicking::panic", Some(64), false));
+        g.add_edge(main, foo, ());
+        g.add_edge(main, panic, ());
+        g.add_edge(foo, panic, ());
+
+        super::apply_hints(
+            &mut g,
+            &[
+                super::parse_unreachable("app::* -> core::panicking::*").unwrap(),
+                super::parse_unreachable("main -> bar").unwrap(),
+            ],
+        );
+
+        assert_eq!(g.edge_count(), 2);
+        assert!(g.find_edge(foo, panic).is_none());
+        assert!(g.find_edge(main, panic).is_some());
+    }
+}
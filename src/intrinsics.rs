@@ -0,0 +1,226 @@
+//! Hand-written stack models for compiler intrinsics and outlined helpers
+//!
+//! Some symbols have no (or wrong) stack usage information: LLVM intrinsics that lower to library
+//! calls on some targets, helpers produced by LLVM's function outliner, routines written in
+//! assembly, etc. The `Intrinsics` registry lets users describe these symbols without having to
+//! wait for a new release of this tool.
+
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, bail};
+
+/// A registry that maps symbol patterns to fixed stack models
+///
+/// A pattern is either an exact symbol name or a prefix followed by `*` (e.g. `llvm.foo.*`).
+/// Patterns are matched against both the mangled and the demangled (hash-less) symbol name. If
+/// several patterns match a symbol the one that was registered last wins.
+#[derive(Clone, Debug, Default)]
+pub struct Intrinsics {
+    models: Vec<(String, u64)>,
+}
+
+impl Intrinsics {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a symbol `pattern` that uses `stack` bytes of stack
+    pub fn register(&mut self, pattern: impl Into<String>, stack: u64) -> &mut Self {
+        self.models.push((pattern.into(), stack));
+        self
+    }
+
+    /// Parses the contents of a configuration file
+    ///
+    /// Each line has the form `<pattern> = <stack>`. Empty lines and lines that start with `#` are
+    /// ignored.
+    pub fn parse(config: &str) -> anyhow::Result<Self> {
+        let mut intrinsics = Self::new();
+        intrinsics.extend_from_str(config)?;
+        Ok(intrinsics)
+    }
+
+    /// Registers all the symbol patterns listed in the configuration file at `path`
+    ///
+    /// See `Intrinsics::parse` for the format of the file
+    pub fn load(&mut self, path: impl AsRef<Path>) -> anyhow::Result<&mut Self> {
+        let path = path.as_ref();
+        let config = fs::read_to_string(path)
+            .map_err(|e| anyhow!("couldn't read `{}`: {}", path.display(), e))?;
+        self.extend_from_str(&config)
+            .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        Ok(self)
+    }
+
+    fn extend_from_str(&mut self, config: &str) -> anyhow::Result<()> {
+        for (i, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.rsplitn(2, '=');
+            let stack = parts.next().expect("UNREACHABLE").trim();
+            let pattern = match parts.next() {
+                Some(pattern) if !pattern.trim().is_empty() => pattern.trim(),
+                _ => bail!("line {}: expected `<pattern> = <stack>`", i + 1),
+            };
+            let stack = stack
+                .parse()
+                .map_err(|e| anyhow!("line {}: invalid stack usage `{}`: {}", i + 1, stack, e))?;
+
+            self.register(pattern, stack);
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if no symbol pattern has been registered
+    pub fn is_empty(&self) -> bool {
+        self.models.is_empty()
+    }
+
+    /// Looks up the stack usage of `symbol`
+    pub fn lookup(&self, symbol: &str) -> Option<u64> {
+        Usage::new(self).lookup(symbol)
+    }
+}
+
+/// Records which patterns of a registry matched a symbol
+pub(crate) struct Usage<'a> {
+    intrinsics: &'a Intrinsics,
+    used: Vec<bool>,
+}
+
+impl<'a> Usage<'a> {
+    pub(crate) fn new(intrinsics: &'a Intrinsics) -> Self {
+        Usage {
+            intrinsics,
+            used: vec![false; intrinsics.models.len()],
+        }
+    }
+
+    // like `Intrinsics::lookup`; all the patterns that match `symbol` are marked as used, not only
+    // the one that wins
+    pub(crate) fn lookup(&mut self, symbol: &str) -> Option<u64> {
+        let mut stack = None;
+        for ((pattern, model), used) in self.intrinsics.models.iter().zip(&mut self.used) {
+            if matches_symbol(pattern, symbol) {
+                *used = true;
+                stack = Some(*model);
+            }
+        }
+
+        stack
+    }
+
+    // patterns that haven't matched any symbol
+    pub(crate) fn unused(&self) -> impl Iterator<Item = &str> {
+        self.intrinsics
+            .models
+            .iter()
+            .zip(&self.used)
+            .filter(|(_, used)| !**used)
+            .map(|((pattern, _), _)| &**pattern)
+    }
+}
+
+// Checks whether `pattern` matches `symbol`
+//
+// This is the pattern syntax used throughout the tool: stack models, fallbacks, unreachable hints
+// and the excludes of batch queries. A pattern is either an exact symbol name or a prefix followed
+// by `*`, and it's matched against both the mangled and the demangled, hash-less symbol name
+pub(crate) fn matches_symbol(pattern: &str, symbol: &str) -> bool {
+    if matches(pattern, symbol) {
+        return true;
+    }
+
+    let demangled = rustc_demangle::demangle(symbol).to_string();
+    let dehashed = crate::dehash(&demangled).unwrap_or(&demangled);
+    matches(pattern, dehashed)
+}
+
+fn matches(pattern: &str, symbol: &str) -> bool {
+    if let Some(prefix) = pattern.strip_suffix('*') {
+        symbol.starts_with(prefix)
+    } else {
+        pattern == symbol
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Intrinsics, Usage};
+
+    #[test]
+    fn lookup() {
+        let mut intrinsics = Intrinsics::new();
+        intrinsics
+            .register("llvm.fshl.*", 0)
+            .register("OUTLINED_FUNCTION_*", 8)
+            .register("OUTLINED_FUNCTION_3", 16)
+            .register("foo::bar", 24);
+
+        assert_eq!(intrinsics.lookup("llvm.fshl.i32"), Some(0));
+        assert_eq!(intrinsics.lookup("llvm.fshr.i32"), None);
+        assert_eq!(intrinsics.lookup("OUTLINED_FUNCTION_1"), Some(8));
+        // the last registered pattern wins
+        assert_eq!(intrinsics.lookup("OUTLINED_FUNCTION_3"), Some(16));
+        // demangled, hash-less name
+        assert_eq!(
+            intrinsics.lookup("_ZN3foo3bar17h0123456789abcdefE"),
+            Some(24)
+        );
+        assert_eq!(intrinsics.lookup("foo::baz"), None);
+    }
+
+    #[test]
+    fn unused() {
+        let mut intrinsics = Intrinsics::new();
+        intrinsics
+            .register("llvm.fshl.*", 0)
+            .register("OUTLINED_FUNCTION_*", 8)
+            .register("OUTLINED_FUNCTION_3", 16);
+
+        let mut usage = Usage::new(&intrinsics);
+        assert_eq!(usage.lookup("OUTLINED_FUNCTION_3"), Some(16));
+        assert_eq!(usage.lookup("foo"), None);
+
+        // shadowed patterns that match are also used
+        assert_eq!(usage.unused().collect::<Vec<_>>(), ["llvm.fshl.*"]);
+    }
+
+    #[test]
+    fn matches_symbol() {
+        use super::matches_symbol;
+
+        // _ZN4core9panicking5panic17h0123456789abcdefE = core::panicking::panic::h0123456789abcdef
+        let panic = "_ZN4core9panicking5panic17h0123456789abcdefE";
+        assert!(matches_symbol(panic, panic));
+        assert!(matches_symbol("core::panicking::panic", panic));
+        assert!(matches_symbol("core::panicking::*", panic));
+        assert!(matches_symbol("_ZN4core*", panic));
+        assert!(!matches_symbol("core::panicking", panic));
+        assert!(!matches_symbol("core::fmt::*", panic));
+    }
+
+    #[test]
+    fn parse() {
+        let intrinsics = Intrinsics::parse(
+            "# outlined helpers
+             OUTLINED_FUNCTION_* = 8
+
+             llvm.fshl.* = 0
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(intrinsics.lookup("OUTLINED_FUNCTION_0"), Some(8));
+        assert_eq!(intrinsics.lookup("llvm.fshl.i64"), Some(0));
+
+        assert!(Intrinsics::parse("foo").is_err());
+        assert!(Intrinsics::parse("= 8").is_err());
+        assert!(Intrinsics::parse("foo = -1").is_err());
+    }
+}
@@ -16,33 +16,100 @@ use std::{
     time::SystemTime,
 };
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use ar::Archive;
-use clap::ValueEnum;
 use log::{error, warn};
 use petgraph::{
     algo,
     graph::{DiGraph, NodeIndex},
-    visit::{Dfs, Reversed, Topo},
+    visit::{Dfs, NodeFiltered, Reversed, Topo},
     Direction, Graph,
 };
-use xmas_elf::{sections::SectionData, symbol_table::Entry, ElfFile};
+use xmas_elf::ElfFile;
+#[cfg(feature = "thumb")]
+use xmas_elf::{sections::SectionData, symbol_table::Entry};
 
+#[cfg(feature = "thumb")]
+use crate::thumb::Tag;
 use crate::{
+    diff::Baseline,
     ir::{FnSig, Item, Stmt, Type},
-    thumb::Tag,
+    splim::LimitWrites,
+    units::Human,
 };
 
+pub use crate::{
+    batch::{load_queries, parse_queries, Query},
+    fallback::{parse_unreachable, Unreachable},
+    intrinsics::Intrinsics,
+    splim::{parse_address, StackLimits},
+    timings::Timings,
+    units::Units,
+};
+
+mod batch;
+mod diff;
+mod fallback;
+mod intrinsics;
 mod ir;
+mod splim;
+mod stats;
+#[cfg(feature = "thumb")]
 mod thumb;
+mod timings;
+mod units;
 // pub mod wrapper;
 
-#[derive(ValueEnum, PartialEq, Debug, Clone, Copy)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
 pub enum OutputFormat {
+    #[default]
     Dot,
     Top,
 }
 
+/// Analysis and output options
+///
+/// New options may be added in minor releases. Build `Options` using the struct update syntax so
+/// that your code keeps compiling when that happens:
+///
+/// ```
+/// use cargo_call_stack::{Options, OutputFormat};
+///
+/// let options = Options {
+///     format: OutputFormat::Top,
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Default)]
+pub struct Options {
+    /// Consider only the call graph that starts from this node
+    pub start: Option<String>,
+    /// Output format
+    pub format: OutputFormat,
+    /// Units used to render byte figures in human-facing formats (`dot` and `top`)
+    pub units: Units,
+    /// Hand-written stack models; these override the stack usage information reported by LLVM
+    pub intrinsics: Intrinsics,
+    /// (ARMv8-M only) check the worst-case stack usage against these stack limit registers and / or
+    /// generate their recommended values
+    pub stack_limits: StackLimits,
+    /// (`dot` format only) highlight the differences with this call graph, previously produced
+    /// by this tool in the `dot` format
+    pub baseline: Option<PathBuf>,
+    /// Answer this batch of queries, in JSON format, instead of producing a call graph
+    pub queries: Vec<Query>,
+    /// Call sites that are asserted to be unreachable; they are removed from the call graph
+    pub unreachable: Vec<Unreachable>,
+    /// Write anonymized statistics about the analysis, in JSON format, to this file
+    pub stats: Option<PathBuf>,
+    /// Print the duration of each phase of the analysis to stderr
+    pub verbose: bool,
+    /// Durations of the phases that ran before the analysis (e.g. `cargo build`); the durations of
+    /// the analysis phases are added to these
+    pub timings: Timings,
+}
+
 // Font used in the dot graphs
 const FONT: &str = "monospace";
 
@@ -51,11 +118,30 @@ pub fn analyze(mut path: PathBuf,
     compiler_builtins_ll_path: String,
     target: &str,
     prefix: String,
-    start: Option<String>,
-    format: OutputFormat
+    options: Options,
 ) -> anyhow::Result<i32> {
+    let mut timings = options.timings.clone();
+    timings.start();
+
     let elf = fs::read(&path)
         .map_err(|e| anyhow!("couldn't open ELF file `{}`: {}", path.display(), e))?;
+    timings.lap("elf read");
+
+    let baseline = if let Some(path) = &options.baseline {
+        if options.format != OutputFormat::Dot {
+            bail!("`--baseline` is only supported by the `dot` output format");
+        }
+
+        let dot = fs::read_to_string(path)
+            .map_err(|e| anyhow!("couldn't read baseline from `{}`: {}", path.display(), e))?;
+        let baseline = diff::Baseline::parse(&dot)
+            .map_err(|e| anyhow!("couldn't parse baseline `{}`: {}", path.display(), e))?;
+        timings.lap("baseline parse");
+
+        Some(baseline)
+    } else {
+        None
+    };
 
     // load llvm-ir file
     let mut ll = None;
@@ -97,8 +183,6 @@ pub fn analyze(mut path: PathBuf,
     let obj = ll_path.with_extension("o");
     let ll = fs::read_to_string(&ll_path)
         .map_err(|e| anyhow!("couldn't read LLVM IR from `{}`: {}", ll_path.display(), e))?;
-    let obj = fs::read(&obj)
-        .map_err(|e| anyhow!("couldn't read object file `{}`: {}", obj.display(), e))?;
 
     let compiler_builtins_ll = fs::read_to_string(&compiler_builtins_ll_path).map_err(|e| {
         anyhow!(
@@ -137,17 +221,31 @@ pub fn analyze(mut path: PathBuf,
             _ => {}
         }
     }
+    timings.lap("ir parse");
 
     // we know how to analyze the machine code in the ELF file for these targets thus we have more
     // information and need less LLVM-IR hacks
     let target_ = match target {
         "thumbv6m-none-eabi" => Target::Thumbv6m,
         "thumbv7m-none-eabi" | "thumbv7em-none-eabi" | "thumbv7em-none-eabihf" => Target::Thumbv7m,
+        "thumbv8m.base-none-eabi" => Target::Thumbv8mBase,
+        "thumbv8m.main-none-eabi" | "thumbv8m.main-none-eabihf" => Target::Thumbv8mMain,
         _ => Target::Other,
     };
 
+    // without the machine code the call graph would be missing edges
+    if target_.is_thumb() && !target_.is_disassembled() {
+        bail!(
+            "this tool was built without the `thumb` feature; it can't analyze programs for the \
+             `{}` target",
+            target
+        );
+    }
+
     // extract stack size information
     // the `.o` file doesn't have address information so we just keep the stack usage information
+    let obj = fs::read(&obj)
+        .map_err(|e| anyhow!("couldn't read object file `{}`: {}", obj.display(), e))?;
     let mut stack_sizes: HashMap<_, _> = stack_sizes::analyze_object(&obj)?
         .into_iter()
         .map(|(name, stack)| (name.to_owned(), stack))
@@ -177,6 +275,8 @@ pub fn analyze(mut path: PathBuf,
         }
     }
 
+    timings.lap("stack sizes");
+
     // extract list of "live" symbols (symbols that have not been GC-ed by the linker)
     // this time we use the ELF and not the object file
     let mut symbols = stack_sizes::analyze_executable(&elf)?;
@@ -202,6 +302,7 @@ pub fn analyze(mut path: PathBuf,
             }
         })
         .collect();
+    timings.lap("elf read");
 
     let mut g = DiGraph::<Node, ()>::new();
     let mut indices = BTreeMap::<Cow<str>, _>::new();
@@ -242,7 +343,17 @@ pub fn analyze(mut path: PathBuf,
     // add all real nodes
     let mut has_stack_usage_info = false;
     let mut has_untyped_symbols = false;
-    let mut addr2name = BTreeMap::new();
+    let mut addr2idx = BTreeMap::new();
+    // symbol names that are defined at more than one address, e.g. `static` C functions or
+    // `#[no_mangle]` symbols that live in different object files
+    let mut duplicates = BTreeMap::<&str, Vec<NodeIndex>>::new();
+    // number of addresses at which each symbol name is defined
+    let mut definitions = HashMap::<&str, usize>::new();
+    for sym in symbols.defined.values() {
+        for name in sym.names() {
+            *definitions.entry(*name).or_default() += 1;
+        }
+    }
     for (address, sym) in &symbols.defined {
         let names = sym.names();
         // filter out tags
@@ -277,12 +388,15 @@ pub fn analyze(mut path: PathBuf,
             aliases.insert(name, canonical_name);
         }
 
-        let _out = addr2name.insert(address, canonical_name);
-        debug_assert!(_out.is_none());
-
-        let stack = stack_sizes.get(canonical_name).cloned();
+        let stack = if definitions[canonical_name] > 1 {
+            // `stack_sizes` is keyed by name so it can't tell apart the functions that share a name;
+            // use the stack usage of this particular definition, if the ELF retained it
+            sym.stack()
+        } else {
+            stack_sizes.get(canonical_name).cloned()
+        };
         if stack.is_none() {
-            if !target_.is_thumb() {
+            if !target_.is_disassembled() {
                 warn!("no stack usage information for `{}`", canonical_name);
             }
         } else {
@@ -295,7 +409,18 @@ pub fn analyze(mut path: PathBuf,
         }
 
         let idx = g.add_node(Node(canonical_name, stack, false));
-        indices.insert(canonical_name.into(), idx);
+        if let Some(first) = indices.get(canonical_name) {
+            // don't merge the nodes; we'll disambiguate them by address
+            duplicates
+                .entry(canonical_name)
+                .or_insert_with(|| vec![*first])
+                .push(idx);
+        } else {
+            indices.insert(canonical_name.into(), idx);
+        }
+
+        let _out = addr2idx.insert(*address, idx);
+        debug_assert!(_out.is_none());
 
         if let Some(def) = names.iter().filter_map(|name| defines.get(name)).next() {
             // if the signature is `fn(&_, &mut fmt::Formatter) -> fmt::Result`
@@ -329,9 +454,18 @@ pub fn analyze(mut path: PathBuf,
         }
     }
 
+    // functions that write to the stack limit registers (ARMv8-M only)
+    #[cfg_attr(not(feature = "thumb"), allow(unused_mut))]
+    let mut limit_writers: Vec<(&str, LimitWrites)> = vec![];
     // to avoid printing several warnings about the same thing
     let mut fns_containing_asm = HashSet::new();
     let mut llvm_seen = HashSet::new();
+    // number of edges added by each resolution method
+    let mut resolution = stats::Resolution::default();
+    // number of nodes whose stack usage comes from a hand-written stack model
+    let mut nodes_with_stack_models = 0;
+    // hand-written stack models; to report the ones that don't match anything
+    let mut models = intrinsics::Usage::new(&options.intrinsics);
     // add edges
     let mut edges: HashMap<_, HashSet<_>> = HashMap::new(); // NodeIdx -> [NodeIdx]
     let mut defined = HashSet::new(); // functions that are `define`-d in the LLVM-IR
@@ -407,7 +541,7 @@ pub fn analyze(mut path: PathBuf,
                         }
                     };
 
-                    if target_.is_thumb() && func.starts_with("llvm.") {
+                    if target_.is_disassembled() && func.starts_with("llvm.") {
                         // we'll analyze the machine code in the ELF file to figure out what these
                         // lower to
                         continue;
@@ -495,6 +629,27 @@ pub fn analyze(mut path: PathBuf,
                         continue;
                     }
 
+                    // the user has told us how much stack this intrinsic uses
+                    if func.starts_with("llvm.") {
+                        if let Some(stack) = models.lookup(func) {
+                            let callee = if let Some(idx) = indices.get(*func) {
+                                *idx
+                            } else {
+                                let idx = g.add_node(Node(*func, Some(stack), false));
+                                indices.insert((*func).into(), idx);
+
+                                idx
+                            };
+
+                            if !callees_seen.contains(&callee) {
+                                callees_seen.insert(callee);
+                                g.add_edge(caller, callee, ());
+                            }
+
+                            continue;
+                        }
+                    }
+
                     assert!(
                         !func.starts_with("llvm."),
                         "BUG: unhandled llvm intrinsic: {}",
@@ -549,11 +704,61 @@ pub fn analyze(mut path: PathBuf,
         }
     }
 
+    // the LLVM-IR refers to functions by name so we can't tell which of the duplicate symbols a
+    // call (or a definition) refers to. Be conservative and give all the duplicates the edges that
+    // were added to the first one
+    for (name, dups) in &duplicates {
+        warn!(
+            "`{}` is defined at {} different addresses; the call graph will contain one node per \
+             address and edges that come from the LLVM-IR will be added to all of them",
+            name,
+            dups.len()
+        );
+
+        let first = dups[0];
+        let callers = g
+            .neighbors_directed(first, Direction::Incoming)
+            .collect::<Vec<_>>();
+        let callees = g
+            .neighbors_directed(first, Direction::Outgoing)
+            .collect::<Vec<_>>();
+
+        for dup in dups.iter().skip(1).copied() {
+            for caller in &callers {
+                if edges.entry(*caller).or_default().insert(dup) {
+                    g.add_edge(*caller, dup, ());
+                }
+            }
+
+            for callee in &callees {
+                if edges.entry(dup).or_default().insert(*callee) {
+                    g.add_edge(dup, *callee, ());
+                }
+            }
+
+            for indirect in indirects.values_mut() {
+                if indirect.callers.contains(&first) {
+                    indirect.callers.insert(dup);
+                }
+            }
+        }
+
+        for (address, idx) in &addr2idx {
+            if dups.contains(idx) {
+                g[*idx].address = Some(*address);
+            }
+        }
+    }
+    resolution.llvm_ir = g.edge_count();
+
+    timings.lap("resolution");
+
     // here we parse the machine code in the ELF file to find out edges that don't appear in the
     // LLVM-IR (e.g. `fadd` operation, `call llvm.umul.with.overflow`, etc.) or are difficult to
     // disambiguate from the LLVM-IR (e.g. does this `llvm.memcpy` lower to a call to
     // `__aebi_memcpy`, a call to `__aebi_memcpy4` or machine instructions?)
-    if target_.is_thumb() {
+    #[cfg(feature = "thumb")]
+    if target_.is_disassembled() {
         let elf = ElfFile::new(&elf).map_err(anyhow::Error::msg)?;
         let sect = elf.find_section_by_name(".symtab").expect("UNREACHABLE");
         let mut tags: Vec<_> = match sect.get_data(&elf).unwrap() {
@@ -602,13 +807,19 @@ pub fn analyze(mut path: PathBuf,
 
                 let start = (address - stext) as usize;
                 let end = start + size as usize;
-                let (bls, bs, indirect, modifies_sp, our_stack) = thumb::analyze(
+                let (bls, bs, indirect, modifies_sp, our_stack, limit_writes) = thumb::analyze(
                     &text[start..end],
                     address,
-                    target_ == Target::Thumbv7m,
+                    target_ == Target::Thumbv7m || target_ == Target::Thumbv8mMain,
+                    target_.is_v8m(),
                     &tags,
                 );
-                let caller = indices[canonical_name];
+                // NOTE not `indices[canonical_name]`; the symbol name may not be unique
+                let caller = addr2idx[&u64::from(address)];
+
+                if limit_writes != LimitWrites::default() {
+                    limit_writers.push((canonical_name, limit_writes));
+                }
 
                 // sanity check
                 if let Some(stack) = our_stack {
@@ -703,11 +914,10 @@ pub fn analyze(mut path: PathBuf,
                 for offset in bls {
                     let addr = (address as i64 + i64::from(offset)) as u64;
                     // address may be off by one due to the thumb bit being set
-                    let name = addr2name
+                    let callee = *addr2idx
                         .get(&addr)
                         .unwrap_or_else(|| panic!("BUG? no symbol at address {}", addr));
 
-                    let callee = indices[*name];
                     if !callees_seen.contains(&callee) {
                         g.add_edge(caller, callee, ());
                         callees_seen.insert(callee);
@@ -721,11 +931,10 @@ pub fn analyze(mut path: PathBuf,
                         // intra-function B branches are not function calls
                     } else {
                         // address may be off by one due to the thumb bit being set
-                        let name = addr2name
+                        let callee = *addr2idx
                             .get(&(addr as u64))
                             .unwrap_or_else(|| panic!("BUG? no symbol at address {}", addr));
 
-                        let callee = indices[*name];
                         if !callees_seen.contains(&callee) {
                             g.add_edge(caller, callee, ());
                             callees_seen.insert(callee);
@@ -737,6 +946,44 @@ pub fn analyze(mut path: PathBuf,
             error!(".text section not found")
         }
     }
+    resolution.machine_code = g.edge_count() - resolution.llvm_ir;
+    timings.lap("disassembly");
+
+    // apply the hand-written stack models
+    if !options.intrinsics.is_empty() {
+        for node in g.node_weights_mut() {
+            if let Some(stack) = models.lookup(&node.name) {
+                if let Local::Exact(local) = node.local {
+                    if local != stack {
+                        warn!(
+                            "`{}` uses {} bytes of stack but the user-provided model says {} \
+                             bytes; overriding the former",
+                            node.name, local, stack
+                        );
+                    }
+                }
+
+                node.local = Local::Exact(stack);
+                has_stack_usage_info = true;
+                nodes_with_stack_models += 1;
+            }
+        }
+
+        for pattern in models.unused() {
+            if target_.is_disassembled() && pattern.starts_with("llvm.") {
+                warn!(
+                    "stack model `{}` has no effect: calls to LLVM intrinsics are resolved using \
+                     the machine code on this target",
+                    pattern
+                );
+            } else {
+                warn!(
+                    "stack model `{}` doesn't match any symbol in the call graph",
+                    pattern
+                );
+            }
+        }
+    }
 
     // add fictitious nodes for indirect function calls
     if has_untyped_symbols {
@@ -839,6 +1086,7 @@ pub fn analyze(mut path: PathBuf,
             // add an edge between this and a potential extern / untyped symbol
             let extern_sym = g.add_node(Node("?", None, false));
             g.add_edge(call, extern_sym, ());
+            resolution.unknown_callees += 1;
         } else {
             if callees.is_empty() {
                 error!("BUG? no callees for `{}`", name);
@@ -850,79 +1098,325 @@ pub fn analyze(mut path: PathBuf,
         }
     }
 
+    resolution.signatures =
+        g.edge_count() - resolution.llvm_ir - resolution.machine_code - resolution.unknown_callees;
+
+    if !options.unreachable.is_empty() {
+        let before = g.edge_count();
+        fallback::apply_hints(&mut g, &options.unreachable);
+        resolution.hints = before - g.edge_count();
+    }
+    timings.lap("resolution");
+
+    let stats = if options.stats.is_some() {
+        Some(stats::Stats::new(
+            target,
+            &g,
+            duplicates.len(),
+            nodes_with_stack_models,
+            resolution,
+        ))
+    } else {
+        None
+    };
+
+    if !options.queries.is_empty() {
+        if options.start.is_some() {
+            bail!("a start point can't be used together with a batch of queries");
+        }
+
+        if !options.stack_limits.is_empty() {
+            bail!("stack limits can't be checked or generated together with a batch of queries");
+        }
+
+        if !has_stack_usage_info {
+            bail!("The graph has zero stack usage information; can't answer the queries");
+        }
+
+        let ec = batch::run(&g, &options.queries, &mut timings)?;
+        if options.verbose {
+            timings.print(&mut io::stderr().lock())?;
+        }
+
+        if let (Some(path), Some(stats)) = (&options.stats, &stats) {
+            stats.write(path, &timings)?;
+        }
+
+        return Ok(ec);
+    }
+
+    // the stack limits are computed from their own entry points, which the filter could remove
+    if options.start.is_some() && !options.stack_limits.is_empty() {
+        bail!("stack limits can't be checked or generated together with a start point");
+    }
+
     // filter the call graph
-    if let Some(start) = &start {
-        let start: &str = start;
-        let start = indices.get(start).cloned().or_else(|| {
-            let start_ = start.to_owned() + "::h";
-            let hits = indices
-                .keys()
-                .filter_map(|key| {
-                    if rustc_demangle::demangle(key)
-                        .to_string()
-                        .starts_with(&start_)
-                    {
-                        Some(key)
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<_>>();
+    if let Some(start) = &options.start {
+        match find_node(&g, start) {
+            Ok(start) => {
+                // replace the old graph
+                g = filter(&g, start, &HashSet::new());
+
+                // invalidate `indices` to prevent misuse
+                indices.clear();
+            }
+
+            Err(FindError::NotFound) => {
+                error!("start point not found; the graph will not be filtered")
+            }
+
+            Err(FindError::Ambiguous) => {
+                error!("start point is ambiguous; the graph will not be filtered")
+            }
+        }
+    }
+
+    let mut cycles = vec![];
+    if !has_stack_usage_info {
+        error!("The graph has zero stack usage information; skipping max stack usage analysis");
+    } else {
+        fallback::solve_fast_path(&mut g);
+        cycles = solve(&mut g);
+    }
+
+    // check the worst-case stack usage against the stack limit registers and / or generate their
+    // recommended values
+    let limits = &options.stack_limits;
+    // whether the worst-case stack usage is known to fit above the stack limit registers
+    let mut limits_ok = true;
+    if !limits.is_empty() {
+        if !target_.is_v8m() {
+            bail!("stack limit registers are only available on ARMv8-M targets");
+        }
+
+        // the firmware may set other limits at runtime
+        for (name, writes) in &limit_writers {
+            let demangled = rustc_demangle::demangle(name);
+            if writes.msplim {
+                warn!("`{}` writes to MSPLIM", demangled);
+            }
+
+            if writes.psplim {
+                warn!("`{}` writes to PSPLIM", demangled);
+            }
+        }
+
+        let fpu = target.ends_with("eabihf");
+        let msp_worst = if let Some(main) = &limits.main {
+            if limits.handlers.is_empty() {
+                warn!(
+                    "no exception handlers were given; assuming that nothing preempts `{}`",
+                    main
+                );
+            }
+
+            let handlers = limits
+                .handlers
+                .iter()
+                .map(|handler| stack_root(&g, handler))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Some(splim::main_stack(stack_root(&g, main)?, &handlers, fpu))
+        } else {
+            None
+        };
+        let threads = limits
+            .threads
+            .iter()
+            .map(|thread| stack_root(&g, thread))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let psp_worst = splim::process_stack(&threads, fpu);
+
+        let elf = ElfFile::new(&elf).map_err(anyhow::Error::msg)?;
+        let msp_top = splim::initial_msp(&elf);
+        if let Some(msplim) = limits.msplim {
+            let worst = msp_worst.ok_or_else(|| {
+                anyhow!("the entry point of the main stack is unknown; can't check MSPLIM")
+            })?;
+
+            if let Some(msp_top) = msp_top {
+                limits_ok &= splim::check("MSPLIM", msplim, msp_top, worst, options.units);
+            } else {
+                error!("initial value of the main stack pointer not found; skipping MSPLIM check");
+                limits_ok = false;
+            }
+        }
+
+        if let Some(psplim) = limits.psplim {
+            let worst = psp_worst.ok_or_else(|| {
+                anyhow!("no thread runs on the process stack; can't check PSPLIM")
+            })?;
 
-            if hits.len() > 1 {
-                error!("multiple matches for `{}`: {:?}", start, hits);
-                None
+            if let Some(psp_top) = limits.psp_top {
+                limits_ok &= splim::check("PSPLIM", psplim, psp_top, worst, options.units);
             } else {
-                hits.first().map(|key| indices[*key])
+                error!("the top of the process stack is unknown; skipping PSPLIM check");
+                limits_ok = false;
             }
-        });
+        }
 
-        if let Some(start) = start {
-            // create a new graph that only contains nodes reachable from `start`
-            let mut g2 = DiGraph::<Node, ()>::new();
+        if let Some(path) = &limits.generate {
+            if msp_worst.is_none() && msp_top.is_some() {
+                warn!("the entry point of the main stack is unknown; MSPLIM won't be generated");
+            }
 
-            // maps `g`'s `NodeIndex`-es to `g2`'s `NodeIndex`-es
-            let mut one2two = BTreeMap::new();
+            if psp_worst.is_none() && limits.psp_top.is_some() {
+                warn!("no thread runs on the process stack; PSPLIM won't be generated");
+            }
 
-            let mut dfs = Dfs::new(&g, start);
-            while let Some(caller1) = dfs.next(&g) {
-                let caller2 = if let Some(i2) = one2two.get(&caller1) {
-                    *i2
-                } else {
-                    let i2 = g2.add_node(g[caller1].clone());
-                    one2two.insert(caller1, i2);
-                    i2
-                };
-
-                let mut callees = g.neighbors(caller1).detach();
-                while let Some((_, callee1)) = callees.next(&g) {
-                    let callee2 = if let Some(i2) = one2two.get(&callee1) {
-                        *i2
-                    } else {
-                        let i2 = g2.add_node(g[callee1].clone());
-                        one2two.insert(callee1, i2);
-                        i2
-                    };
+            splim::generate(
+                path,
+                limits.margin,
+                msp_top.zip(msp_worst),
+                limits.psp_top.zip(psp_worst),
+            )?;
+        }
+    }
 
-                    g2.add_edge(caller2, callee2, ());
-                }
+    timings.lap("solve");
+
+    // here we try to shorten the name of the symbol if it doesn't result in ambiguity
+    for node in g.node_weights_mut() {
+        let demangled = rustc_demangle::demangle(&node.name).to_string();
+
+        if let Some(dehashed) = dehash(&demangled) {
+            if ambiguous[dehashed] == 1 {
+                node.name = Cow::Owned(dehashed.to_owned());
             }
+        }
+    }
 
-            // replace the old graph
-            g = g2;
+    match options.format {
+        OutputFormat::Dot => dot(&g, &cycles, baseline.as_ref(), options.units)?,
+        OutputFormat::Top => top(g, options.units)?,
+    }
+    timings.lap("render");
+
+    if options.verbose {
+        timings.print(&mut io::stderr().lock())?;
+    }
+
+    if let (Some(path), Some(stats)) = (&options.stats, &stats) {
+        stats.write(path, &timings)?;
+    }
 
-            // invalidate `indices` to prevent misuse
-            indices.clear();
+    Ok(if limits_ok { 0 } else { 1 })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FindError {
+    NotFound,
+    // more than one node matches the name
+    Ambiguous,
+}
+
+// finds the node named `name`; the hash of the symbol name can be omitted. Symbols that share a
+// name can be told apart by their address, as printed in the output, e.g. `helper @ 0x08000100`
+fn find_node(g: &Graph<Node, ()>, name: &str) -> Result<NodeIndex, FindError> {
+    let (name, address) = match name.rsplit_once(" @ ") {
+        Some((name, address)) => {
+            let address = address
+                .strip_prefix("0x")
+                .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+                .ok_or(FindError::NotFound)?;
+
+            (name, Some(address))
+        }
+        None => (name, None),
+    };
+
+    // function pointers, trait objects and unknown callees are not functions
+    let candidates = g.node_indices().filter(|idx| {
+        let node = &g[*idx];
+        !node.dashed && node.name != "?" && (address.is_none() || node.address == address)
+    });
+
+    let mut hits = candidates
+        .clone()
+        .filter(|idx| g[*idx].name == name)
+        .collect::<Vec<_>>();
+    if hits.is_empty() {
+        let name_ = name.to_owned() + "::h";
+        hits = candidates
+            .filter(|idx| {
+                let demangled = rustc_demangle::demangle(&g[*idx].name).to_string();
+                demangled == name || demangled.starts_with(&name_)
+            })
+            .collect();
+    }
+
+    match hits[..] {
+        [] => Err(FindError::NotFound),
+        [idx] => Ok(idx),
+        _ => {
+            error!(
+                "multiple matches for `{}`: {:?}",
+                name,
+                hits.iter().map(|idx| g[*idx].label()).collect::<Vec<_>>()
+            );
+            Err(FindError::Ambiguous)
+        }
+    }
+}
+
+// worst-case stack usage of the entry point `name` of the code that runs on a stack
+fn stack_root(g: &Graph<Node, ()>, name: &str) -> anyhow::Result<Max> {
+    match find_node(g, name) {
+        // no stack usage information at all; the usage is at least zero
+        Ok(idx) => Ok(g[idx].max.unwrap_or(Max::LowerBound(0))),
+        Err(FindError::NotFound) => bail!("stack entry point `{}` not found", name),
+        Err(FindError::Ambiguous) => bail!("stack entry point `{}` is ambiguous", name),
+    }
+}
+
+// creates a new graph that only contains the nodes reachable from `start`; paths that go through
+// `excluded` nodes are not followed
+fn filter<'a>(
+    g: &Graph<Node<'a>, ()>,
+    start: NodeIndex,
+    excluded: &HashSet<NodeIndex>,
+) -> Graph<Node<'a>, ()> {
+    let mut g2 = DiGraph::<Node, ()>::new();
+
+    // maps `g`'s `NodeIndex`-es to `g2`'s `NodeIndex`-es
+    let mut one2two = BTreeMap::new();
+
+    let filtered = NodeFiltered::from_fn(g, |node| !excluded.contains(&node));
+    let mut dfs = Dfs::new(&filtered, start);
+    while let Some(caller1) = dfs.next(&filtered) {
+        let caller2 = if let Some(i2) = one2two.get(&caller1) {
+            *i2
         } else {
-            error!("start point not found; the graph will not be filtered")
+            let i2 = g2.add_node(g[caller1].clone());
+            one2two.insert(caller1, i2);
+            i2
+        };
+
+        let mut callees = g.neighbors(caller1).detach();
+        while let Some((_, callee1)) = callees.next(g) {
+            if excluded.contains(&callee1) {
+                continue;
+            }
+
+            let callee2 = if let Some(i2) = one2two.get(&callee1) {
+                *i2
+            } else {
+                let i2 = g2.add_node(g[callee1].clone());
+                one2two.insert(callee1, i2);
+                i2
+            };
+
+            g2.add_edge(caller2, callee2, ());
         }
     }
 
+    g2
+}
+
+// computes the maximum stack usage of every node in the graph; returns the cycles in the graph
+fn solve(g: &mut Graph<Node, ()>) -> Vec<Vec<NodeIndex>> {
     let mut cycles = vec![];
-    if !has_stack_usage_info {
-        error!("The graph has zero stack usage information; skipping max stack usage analysis");
-    } else if algo::is_cyclic_directed(&g) {
-        let sccs = algo::kosaraju_scc(&g);
+    if algo::is_cyclic_directed(&*g) {
+        let sccs = algo::kosaraju_scc(&*g);
 
         // iterate over SCCs (Strongly Connected Components) in reverse topological order
         for scc in &sccs {
@@ -983,8 +1477,8 @@ pub fn analyze(mut path: PathBuf,
         }
     } else {
         // compute max stack usage
-        let mut topo = Topo::new(Reversed(&g));
-        while let Some(node) = topo.next(Reversed(&g)) {
+        let mut topo = Topo::new(Reversed(&*g));
+        while let Some(node) = topo.next(Reversed(&*g)) {
             debug_assert!(g[node].max.is_none());
 
             let neighbors_max = max_of(
@@ -1000,80 +1494,172 @@ pub fn analyze(mut path: PathBuf,
         }
     }
 
-    // here we try to shorten the name of the symbol if it doesn't result in ambiguity
-    for node in g.node_weights_mut() {
-        let demangled = rustc_demangle::demangle(&node.name).to_string();
-
-        if let Some(dehashed) = dehash(&demangled) {
-            if ambiguous[dehashed] == 1 {
-                node.name = Cow::Owned(dehashed.to_owned());
-            }
-        }
-    }
-
-    match format {
-        OutputFormat::Dot => dot(g, &cycles)?,
-        OutputFormat::Top => top(g)?,
-    }
-
-    Ok(0)
+    cycles
 }
 
-fn dot(g: Graph<Node, ()>, cycles: &[Vec<NodeIndex>]) -> io::Result<()> {
+fn dot(
+    g: &Graph<Node, ()>,
+    cycles: &[Vec<NodeIndex>],
+    baseline: Option<&Baseline>,
+    units: Units,
+) -> io::Result<()> {
     let stdout = io::stdout();
     let mut stdout = stdout.lock();
 
-    writeln!(stdout, "digraph {{")?;
-    writeln!(stdout, "    node [fontname={} shape=box]", FONT)?;
+    render_dot(&mut stdout, g, cycles, baseline, units)
+}
+
+// with a `baseline`, added nodes and edges are green, removed ones are red and changed stack usage
+// values are annotated with their baseline value
+fn render_dot(
+    w: &mut impl Write,
+    g: &Graph<Node, ()>,
+    cycles: &[Vec<NodeIndex>],
+    baseline: Option<&Baseline>,
+    units: Units,
+) -> io::Result<()> {
+    writeln!(w, "digraph {{")?;
+    writeln!(w, "    node [fontname={} shape=box]", FONT)?;
+
+    let keys = if baseline.is_some() {
+        diff::graph_keys(g)
+    } else {
+        vec![]
+    };
 
     for (i, node) in g.raw_nodes().iter().enumerate() {
         let node = &node.weight;
+        let old = baseline.and_then(|baseline| baseline.nodes.get(&keys[i]));
 
-        write!(stdout, "    {} [label=\"", i,)?;
+        write!(w, "    {} [label=\"", i,)?;
 
-        let mut escaper = Escaper::new(&mut stdout);
-        write!(escaper, "{}", rustc_demangle::demangle(&node.name)).ok();
+        let mut escaper = Escaper::new(&mut *w);
+        write!(escaper, "{}", node.label()).ok();
         escaper.error?;
 
         if let Some(max) = node.max {
-            write!(stdout, "\\nmax {}", max)?;
+            write!(w, "\\nmax {}", Human(max, units))?;
+
+            match old.map(|old| old.max) {
+                Some(old_max) if old_max != Some(max) => match old_max {
+                    Some(Max::Exact(n)) => write!(w, " (was {})", Human(n, units))?,
+                    Some(Max::LowerBound(n)) => write!(w, " (was >= {})", Human(n, units))?,
+                    None => write!(w, " (was ?)")?,
+                },
+                _ => {}
+            }
+        }
+
+        if let Some(fast_max) = node.fast_max.filter(|fast_max| Some(*fast_max) != node.max) {
+            write!(w, "\\nfast path max {}", Human(fast_max, units))?;
         }
 
-        write!(stdout, "\\nlocal = {}\"", node.local,)?;
+        write!(w, "\\nlocal = {}", Human(node.local, units))?;
+        if let Some(old) = old.filter(|old| old.local != node.local) {
+            write!(w, " (was {})", Human(old.local, units))?;
+        }
+        write!(w, "\"")?;
 
         if node.dashed {
-            write!(stdout, " style=dashed")?;
+            write!(w, " style=dashed")?;
+        }
+
+        if baseline.is_some() && old.is_none() {
+            write!(w, " color={0} fontcolor={0}", diff::ADDED)?;
         }
 
-        writeln!(stdout, "]")?;
+        writeln!(w, "]")?;
+    }
+
+    // key -> `dot` node identifier, including the removed nodes
+    let mut ids = keys
+        .iter()
+        .enumerate()
+        .map(|(i, key)| (key, i))
+        .collect::<BTreeMap<_, _>>();
+
+    if let Some(baseline) = baseline {
+        let mut i = g.node_count();
+        for (key, old) in &baseline.nodes {
+            if ids.contains_key(key) {
+                continue;
+            }
+
+            write!(w, "    {} [label=\"", i)?;
+
+            let mut escaper = Escaper::new(&mut *w);
+            write!(escaper, "{}", key.0).ok();
+            escaper.error?;
+
+            if let Some(max) = old.max {
+                write!(w, "\\nmax {}", Human(max, units))?;
+            }
+            write!(w, "\\nlocal = {}\"", Human(old.local, units))?;
+
+            if old.dashed {
+                write!(w, " style=dashed")?;
+            }
+
+            writeln!(w, " color={0} fontcolor={0}]", diff::REMOVED)?;
+
+            ids.insert(key, i);
+            i += 1;
+        }
     }
 
     for edge in g.raw_edges() {
-        writeln!(
-            stdout,
-            "    {} -> {}",
-            edge.source().index(),
-            edge.target().index()
-        )?;
+        let (from, to) = (edge.source().index(), edge.target().index());
+        write!(w, "    {} -> {}", from, to)?;
+
+        if let Some(baseline) = baseline {
+            if !baseline
+                .edges
+                .contains(&(keys[from].clone(), keys[to].clone()))
+            {
+                write!(w, " [color={}]", diff::ADDED)?;
+            }
+        }
+
+        writeln!(w)?;
+    }
+
+    if let Some(baseline) = baseline {
+        let edges = g
+            .raw_edges()
+            .iter()
+            .map(|edge| (&keys[edge.source().index()], &keys[edge.target().index()]))
+            .collect::<HashSet<_>>();
+
+        for (from, to) in &baseline.edges {
+            if !edges.contains(&(from, to)) {
+                writeln!(
+                    w,
+                    "    {} -> {} [color={}]",
+                    ids[from],
+                    ids[to],
+                    diff::REMOVED
+                )?;
+            }
+        }
     }
 
     for (i, cycle) in cycles.iter().enumerate() {
-        writeln!(stdout, "\n    subgraph cluster_{} {{", i)?;
-        writeln!(stdout, "        style=dashed")?;
-        writeln!(stdout, "        fontname={}", FONT)?;
-        writeln!(stdout, "        label=\"SCC{}\"", i)?;
+        writeln!(w, "\n    subgraph cluster_{} {{", i)?;
+        writeln!(w, "        style=dashed")?;
+        writeln!(w, "        fontname={}", FONT)?;
+        writeln!(w, "        label=\"SCC{}\"", i)?;
 
         for node in cycle {
-            writeln!(stdout, "        {}", node.index())?;
+            writeln!(w, "        {}", node.index())?;
         }
 
-        writeln!(stdout, "    }}")?;
+        writeln!(w, "    }}")?;
     }
 
-    writeln!(stdout, "}}")
+    writeln!(w, "}}")
 }
 
-pub(crate) fn top(g: Graph<Node, ()>) -> io::Result<()> {
+pub(crate) fn top(g: Graph<Node, ()>, units: Units) -> io::Result<()> {
     let stdout = io::stdout();
     let mut stdout = stdout.lock();
 
@@ -1089,10 +1675,13 @@ pub(crate) fn top(g: Graph<Node, ()>) -> io::Result<()> {
         writeln!(
             stdout,
             "{} MAX",
-            match max {
-                Max::Exact(n) => n,
-                Max::LowerBound(n) => n,
-            }
+            Human(
+                match max {
+                    Max::Exact(n) => n,
+                    Max::LowerBound(n) => n,
+                },
+                units
+            )
         )?;
     }
 
@@ -1105,13 +1694,13 @@ pub(crate) fn top(g: Graph<Node, ()>) -> io::Result<()> {
     });
 
     for node in nodes.iter() {
-        let name = rustc_demangle::demangle(&node.name);
+        let name = node.label();
         let val: u64 = if let Local::Exact(n) = node.local {
             n
         } else {
             0
         };
-        write!(stdout, "{} ", val)?;
+        write!(stdout, "{} ", Human(val, units))?;
 
         let mut escaper = Escaper::new(&mut stdout);
         writeln!(escaper, "{}", name).ok();
@@ -1176,9 +1765,25 @@ struct Node<'a> {
     name: Cow<'a, str>,
     local: Local,
     max: Option<Max>,
+    // maximum stack usage when the calls into runtime fallbacks (e.g. panics) are not followed
+    fast_max: Option<Max>,
+    // only set when the symbol name is shared by several nodes
+    address: Option<u64>,
     dashed: bool,
 }
 
+impl Node<'_> {
+    // demangled name, plus address if the name is not unique
+    fn label(&self) -> String {
+        let name = rustc_demangle::demangle(&self.name).to_string();
+        if let Some(address) = self.address {
+            format!("{} @ {:#010x}", name, address)
+        } else {
+            name
+        }
+    }
+}
+
 #[allow(non_snake_case)]
 fn Node<'a, S>(name: S, stack: Option<u64>, dashed: bool) -> Node<'a>
 where
@@ -1188,6 +1793,8 @@ where
         name: name.into(),
         local: stack.map(Local::Exact).unwrap_or(Local::Unknown),
         max: None,
+        fast_max: None,
+        address: None,
         dashed,
     }
 }
@@ -1199,15 +1806,6 @@ enum Local {
     Unknown,
 }
 
-impl fmt::Display for Local {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            Local::Exact(n) => write!(f, "{}", n),
-            Local::Unknown => f.write_str("?"),
-        }
-    }
-}
-
 impl Into<Max> for Local {
     fn into(self) -> Max {
         match self {
@@ -1262,15 +1860,6 @@ fn max(lhs: Max, rhs: Max) -> Max {
     }
 }
 
-impl fmt::Display for Max {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            Max::Exact(n) => write!(f, "= {}", n),
-            Max::LowerBound(n) => write!(f, ">= {}", n),
-        }
-    }
-}
-
 // used to track indirect function calls (`fn` pointers)
 #[derive(Default, Debug)]
 struct Indirect {
@@ -1304,15 +1893,31 @@ enum Target {
     Other,
     Thumbv6m,
     Thumbv7m,
+    Thumbv8mBase,
+    Thumbv8mMain,
 }
 
 impl Target {
     fn is_thumb(&self) -> bool {
         match *self {
-            Target::Thumbv6m | Target::Thumbv7m => true,
+            Target::Thumbv6m | Target::Thumbv7m | Target::Thumbv8mBase | Target::Thumbv8mMain => {
+                true
+            }
             Target::Other => false,
         }
     }
+
+    // whether we analyze the machine code of this target
+    fn is_disassembled(&self) -> bool {
+        cfg!(feature = "thumb") && self.is_thumb()
+    }
+
+    fn is_v8m(&self) -> bool {
+        match *self {
+            Target::Thumbv8mBase | Target::Thumbv8mMain => true,
+            Target::Other | Target::Thumbv6m | Target::Thumbv7m => false,
+        }
+    }
 }
 
 // LLVM's function outliner pass produces symbols of the form `OUTLINED_FUNCTION_NNN` where `NNN` is
@@ -3,20 +3,23 @@
 use core::str;
 use std::{
     env,
+    ffi::OsString,
+    fs::{self, File},
     io::{BufRead, BufReader},
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{self, Command, Stdio},
-    time::SystemTime,
+    time::Instant,
 };
 
 use anyhow::bail;
 use cargo_project::{Artifact, Profile, Project};
-use clap::{Parser};
+use clap::Parser;
 use env_logger::{Builder, Env};
-use filetime::FileTime;
-use walkdir::WalkDir;
+use fs2::FileExt;
 
-use cargo_call_stack::OutputFormat;
+use cargo_call_stack::{
+    Intrinsics, Options, OutputFormat, StackLimits, Timings, Units, Unreachable,
+};
 
 mod wrapper;
 
@@ -52,6 +55,100 @@ struct Args {
     #[arg(long, default_value = "dot")]
     format: OutputFormat,
 
+    /// Units used to render byte figures in the `dot` and `top` formats
+    #[arg(long, default_value = "bytes")]
+    units: Units,
+
+    /// File with hand-written stack models (`<pattern> = <stack>` lines)
+    #[arg(long, value_name = "FILE")]
+    intrinsics: Option<PathBuf>,
+
+    /// (ARMv8-M) check that the worst-case stack usage fits above this MSPLIM value
+    #[arg(
+        long,
+        value_name = "ADDR",
+        value_parser = cargo_call_stack::parse_address,
+        conflicts_with = "start",
+        requires = "main_root"
+    )]
+    msplim: Option<u32>,
+
+    /// (ARMv8-M) check that the worst-case stack usage fits above this PSPLIM value
+    #[arg(
+        long,
+        value_name = "ADDR",
+        value_parser = cargo_call_stack::parse_address,
+        conflicts_with = "start",
+        requires_all = ["psp_top", "thread"]
+    )]
+    psplim: Option<u32>,
+
+    /// (ARMv8-M) initial value of the process stack pointer
+    #[arg(long, value_name = "ADDR", value_parser = cargo_call_stack::parse_address)]
+    psp_top: Option<u32>,
+
+    /// (ARMv8-M) write the recommended MSPLIM / PSPLIM values, as Rust constants, to this file
+    #[arg(long, value_name = "FILE", conflicts_with = "start")]
+    gen_stack_limits: Option<PathBuf>,
+
+    /// Stack space, in bytes, to leave between the worst-case stack usage and the generated limits
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 0,
+        requires = "gen_stack_limits"
+    )]
+    stack_limit_margin: u32,
+
+    /// (ARMv8-M) entry point of the code that runs on the main stack in thread mode, e.g. `main`
+    #[arg(long, value_name = "SYMBOL")]
+    main_root: Option<String>,
+
+    /// (ARMv8-M) exception handler; handlers run on the main stack and can preempt the main root
+    /// and each other (can be repeated)
+    #[arg(long, value_name = "SYMBOL", requires = "main_root")]
+    handler: Vec<String>,
+
+    /// (ARMv8-M) entry point of a thread that runs on the process stack (can be repeated)
+    #[arg(long, value_name = "SYMBOL")]
+    thread: Vec<String>,
+
+    /// Highlight the differences with this call graph, previously produced in the `dot` format
+    #[arg(long, value_name = "DOT_FILE")]
+    baseline: Option<PathBuf>,
+
+    /// Answer the stack usage queries in this file and print the answers in JSON format
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "start",
+            "baseline",
+            "format",
+            "units",
+            "msplim",
+            "psplim",
+            "gen_stack_limits",
+            "main_root",
+            "handler",
+            "thread",
+        ]
+    )]
+    queries: Option<PathBuf>,
+
+    /// Remove the call sites from CALLER to CALLEE, asserting they are unreachable (can be repeated)
+    #[arg(
+        long,
+        value_name = "CALLER -> CALLEE",
+        value_parser = cargo_call_stack::parse_unreachable
+    )]
+    unreachable: Vec<Unreachable>,
+
+    /// Write anonymized statistics about the analysis (graph size, resolution methods, timings,
+    /// etc.), in JSON format, to this file
+    #[arg(long, value_name = "FILE")]
+    stats: Option<PathBuf>,
+
     /// consider only the call graph that starts from this node
     start: Option<String>,
 }
@@ -77,6 +174,17 @@ fn run() -> anyhow::Result<i32> {
     let args = Args::parse();
     let profile = Profile::Release;
 
+    let mut intrinsics = Intrinsics::new();
+    if let Some(path) = &args.intrinsics {
+        intrinsics.load(path)?;
+    }
+
+    let queries = if let Some(path) = &args.queries {
+        cargo_call_stack::load_queries(path)?
+    } else {
+        vec![]
+    };
+
     let file = match (&args.example, &args.bin) {
         (Some(f), None) => f,
         (None, Some(f)) => f,
@@ -90,6 +198,30 @@ fn run() -> anyhow::Result<i32> {
     let target_flag = args.target.as_deref();
     let target = project.target().or(target_flag).unwrap_or(&host);
 
+    // fail before spending time on the build
+    let stack_limits =
+        args.msplim.is_some() || args.psplim.is_some() || args.gen_stack_limits.is_some();
+    if stack_limits && !target.starts_with("thumbv8m") {
+        bail!("stack limit registers are only available on ARMv8-M targets");
+    }
+
+    // all the artifacts produced by this invocation are placed in a directory specific to the build
+    // arguments so that concurrent invocations in the same workspace (e.g. CI jobs of a build
+    // matrix) don't trample each other's artifacts
+    let target_dir = project.target_dir().join("call-stack").join(format!(
+        "{:016x}",
+        build_hash(&args, target, project.toml(), &build_env())
+    ));
+    fs::create_dir_all(&target_dir)?;
+
+    // invocations with the same build arguments share the directory; serialize them. The lock is
+    // released when `lock` is dropped, i.e. after the analysis is done reading the artifacts
+    let lock = File::create(target_dir.join(".lock"))?;
+    if lock.try_lock_exclusive().is_err() {
+        eprintln!("Blocking waiting for file lock on {}", target_dir.display());
+        lock.lock_exclusive()?;
+    }
+
     let mut is_no_std = false;
     {
         let output = Command::new("rustc")
@@ -113,6 +245,8 @@ fn run() -> anyhow::Result<i32> {
         cargo.args(&["--target", target]);
     }
 
+    cargo.arg("--target-dir").arg(&target_dir);
+
     if args.all_features {
         cargo.arg("--all-features");
     } else if let Some(features) = &args.features {
@@ -154,31 +288,25 @@ fn run() -> anyhow::Result<i32> {
     cargo.env("RUSTC_WRAPPER", env::current_exe()?);
     cargo.stderr(Stdio::piped());
 
-    // "touch" some source file to trigger a rebuild
-    let root = project.toml().parent().expect("UNREACHABLE");
-    let now = FileTime::from_system_time(SystemTime::now());
-    if !filetime::set_file_times(root.join("src/main.rs"), now, now).is_ok() {
-        if !filetime::set_file_times(root.join("src/lib.rs"), now, now).is_ok() {
-            // look for some rust source file and "touch" it
-            let src = root.join("src");
-            let haystack = if src.exists() { &src } else { root };
-
-            for entry in WalkDir::new(haystack) {
-                let entry = entry?;
-                let path = entry.path();
-
-                if path.extension().map(|ext| ext == "rs").unwrap_or(false) {
-                    filetime::set_file_times(path, now, now)?;
-                    break;
-                }
-            }
-        }
+    // force a rebuild of the project's crates so that the wrapper reports the paths we need. We
+    // remove their fingerprints, which live in this invocation's target directory, instead of
+    // "touching" one of their source files, which are shared by all invocations
+    let mut profile_dir = target_dir.clone();
+    if let Some(target) = target_flag.or(project.target()) {
+        profile_dir.push(target);
     }
+    profile_dir.push(if profile.is_release() {
+        "release"
+    } else {
+        "debug"
+    });
+    remove_fingerprints(&profile_dir.join(".fingerprint"), project.name())?;
 
     if args.verbose {
         eprintln!("{:?}", cargo);
     }
 
+    let build_start = Instant::now();
     let mut child = cargo.spawn()?;
     let stderr = BufReader::new(child.stderr.take().unwrap());
     let mut compiler_builtins_rlib_path = None;
@@ -197,6 +325,8 @@ fn run() -> anyhow::Result<i32> {
     }
 
     let status = child.wait()?;
+    let mut timings = Timings::new();
+    timings.record("cargo build", build_start.elapsed());
 
     if !status.success() {
         return Ok(status.code().unwrap_or(1));
@@ -212,9 +342,265 @@ fn run() -> anyhow::Result<i32> {
     } else {
         project.path(Artifact::Bin(file), profile, target_flag, &host)?
     };
+    // relocate the path into this invocation's target directory
+    let path = target_dir.join(path.strip_prefix(project.target_dir())?);
 
     let prefix = format!("{}-", file.replace('-', "_"));
     let target = project.target().or(target_flag).unwrap_or(&host);
 
-    cargo_call_stack::analyze(path, compiler_builtins_rlib_path, compiler_builtins_ll_path, target, prefix, args.start, args.format)
+    let options = Options {
+        start: args.start,
+        format: args.format,
+        units: args.units,
+        intrinsics,
+        stack_limits: StackLimits {
+            msplim: args.msplim,
+            psplim: args.psplim,
+            psp_top: args.psp_top,
+            generate: args.gen_stack_limits,
+            margin: args.stack_limit_margin,
+            main: args.main_root,
+            handlers: args.handler,
+            threads: args.thread,
+        },
+        baseline: args.baseline,
+        queries,
+        unreachable: args.unreachable,
+        stats: args.stats,
+        verbose: args.verbose,
+        timings,
+    };
+
+    cargo_call_stack::analyze(
+        path,
+        compiler_builtins_rlib_path,
+        compiler_builtins_ll_path,
+        target,
+        prefix,
+        options,
+    )
+}
+
+// environment variables that affect the build
+const BUILD_ENV: &[&str] = &[
+    "RUSTFLAGS",
+    "CARGO_ENCODED_RUSTFLAGS",
+    "CARGO_BUILD_RUSTFLAGS",
+];
+
+fn build_env() -> Vec<(&'static str, Option<OsString>)> {
+    BUILD_ENV
+        .iter()
+        .map(|var| (*var, env::var_os(var)))
+        .collect()
+}
+
+// hash of the arguments, manifest and environment that affect the build
+//
+// The hash is 64-bit FNV-1a over the fields below, in that order. Each field is encoded as a `0`
+// byte when absent or as a `1` byte followed by its length (little endian `u64`) and its bytes when
+// present. A fixed algorithm is used (rather than `DefaultHasher`, whose output may change between
+// Rust releases) so that updating the toolchain doesn't orphan the existing build directories
+fn build_hash(args: &Args, target: &str, manifest: &Path, env: &[(&str, Option<OsString>)]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let mut hash = OFFSET_BASIS;
+    let mut write = |field: Option<&[u8]>| {
+        let mut bytes = vec![];
+        if let Some(field) = field {
+            bytes.push(1);
+            bytes.extend_from_slice(&(field.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(field);
+        } else {
+            bytes.push(0);
+        }
+
+        for byte in bytes {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(PRIME);
+        }
+    };
+
+    write(Some(target.as_bytes()));
+    write(Some(manifest.to_string_lossy().as_bytes()));
+    write(args.bin.as_ref().map(|bin| bin.as_bytes()));
+    write(args.example.as_ref().map(|example| example.as_bytes()));
+    write(args.features.as_ref().map(|features| features.as_bytes()));
+    write(Some(&[args.all_features as u8]));
+    for (var, value) in env {
+        write(Some(var.as_bytes()));
+        let value = value.as_ref().map(|value| value.to_string_lossy());
+        write(value.as_deref().map(str::as_bytes));
+    }
+    hash
+}
+
+// removes the fingerprints of the crates of package `name`; fingerprints are named
+// `$name-$hash`
+fn remove_fingerprints(dir: &Path, name: &str) -> anyhow::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // nothing has been built yet
+        Err(_) => return Ok(()),
+    };
+
+    let prefix = format!("{}-", name);
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let is_ours = file_name
+            .to_str()
+            .and_then(|file_name| file_name.strip_prefix(&prefix))
+            .map(|hash| hash.bytes().all(|b| b.is_ascii_hexdigit()))
+            .unwrap_or(false);
+
+        if is_ours {
+            fs::remove_dir_all(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsString, fs, path::Path};
+
+    use clap::Parser;
+
+    use super::Args;
+
+    #[test]
+    fn build_hash() {
+        let hash = |args: &[&str], manifest: &str, rustflags: Option<&str>| {
+            let args = Args::parse_from(Some("cargo-call-stack").iter().chain(args));
+            super::build_hash(
+                &args,
+                "thumbv7m-none-eabi",
+                Path::new(manifest),
+                &[("RUSTFLAGS", rustflags.map(OsString::from))],
+            )
+        };
+
+        let app = hash(&["--bin", "app"], "/ws/app/Cargo.toml", None);
+        // the hash must not change between Rust releases
+        assert_eq!(app, 0x43d6_ef41_7168_cf0c);
+        assert_eq!(app, hash(&["--bin", "app"], "/ws/app/Cargo.toml", None));
+        // flags that don't affect the build
+        assert_eq!(
+            app,
+            hash(
+                &["--bin", "app", "--format", "top"],
+                "/ws/app/Cargo.toml",
+                None
+            )
+        );
+
+        assert_ne!(app, hash(&["--example", "app"], "/ws/app/Cargo.toml", None));
+        assert_ne!(app, hash(&["--bin", "app"], "/ws/other/Cargo.toml", None));
+        assert_ne!(
+            app,
+            hash(
+                &["--bin", "app"],
+                "/ws/app/Cargo.toml",
+                Some("-C opt-level=s")
+            )
+        );
+    }
+
+    #[test]
+    fn stack_limit_margin() {
+        let parse =
+            |args: &[&str]| Args::try_parse_from(Some("cargo-call-stack").iter().chain(args));
+
+        assert!(parse(&[
+            "--gen-stack-limits",
+            "limits.rs",
+            "--stack-limit-margin",
+            "8"
+        ])
+        .is_ok());
+        assert!(parse(&["--stack-limit-margin", "8"]).is_err());
+        assert!(parse(&[]).is_ok());
+    }
+
+    #[test]
+    fn stack_roots() {
+        let parse =
+            |args: &[&str]| Args::try_parse_from(Some("cargo-call-stack").iter().chain(args));
+
+        assert!(parse(&["--msplim", "0x2000_0000", "--main-root", "main"]).is_ok());
+        assert!(parse(&[
+            "--msplim",
+            "0x2000_0000",
+            "--main-root",
+            "main",
+            "--handler",
+            "SysTick",
+            "--handler",
+            "UART0"
+        ])
+        .is_ok());
+        assert!(parse(&[
+            "--psplim",
+            "0x2000_0000",
+            "--psp-top",
+            "0x2000_1000",
+            "--thread",
+            "worker"
+        ])
+        .is_ok());
+
+        // the worst-case stack usage can't be computed without the entry points
+        assert!(parse(&["--msplim", "0x2000_0000"]).is_err());
+        assert!(parse(&["--psplim", "0x2000_0000", "--psp-top", "0x2000_1000"]).is_err());
+        assert!(parse(&["--handler", "SysTick"]).is_err());
+        // the start point would filter out the entry points
+        assert!(parse(&["--gen-stack-limits", "limits.rs", "main"]).is_err());
+    }
+
+    #[test]
+    fn queries() {
+        let parse = |args: &[&str]| {
+            Args::try_parse_from(
+                ["cargo-call-stack", "--queries", "queries.txt"]
+                    .iter()
+                    .chain(args),
+            )
+        };
+
+        assert!(parse(&[]).is_ok());
+        // these flags would be ignored
+        assert!(parse(&["--format", "top"]).is_err());
+        assert!(parse(&["--msplim", "0x2000_0000"]).is_err());
+        assert!(parse(&["--gen-stack-limits", "limits.rs"]).is_err());
+        assert!(parse(&["main"]).is_err());
+    }
+
+    #[test]
+    fn remove_fingerprints() {
+        let dir =
+            std::env::temp_dir().join(format!("call-stack-fingerprints-{}", std::process::id()));
+        for name in &[
+            "app-0123456789abcdef",
+            "app-util-fedcba9876543210",
+            "core-0123456789abcdef",
+        ] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+        }
+
+        super::remove_fingerprints(&dir, "app").unwrap();
+
+        let mut left = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, ["app-util-fedcba9876543210", "core-0123456789abcdef"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+        // a missing directory is not an error
+        super::remove_fingerprints(&dir, "app").unwrap();
+    }
 }
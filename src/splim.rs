@@ -0,0 +1,337 @@
+//! Stack limit registers (`MSPLIM` and `PSPLIM`) of ARMv8-M devices
+//!
+//! On ARMv8-M the hardware raises an exception when a stack pointer goes below the value of its
+//! stack limit register. Here we check whether the worst-case stack usage computed by the analysis
+//! fits between the top of a stack and its configured limit, and generate the recommended values
+//! of the stack limit registers so that the firmware can set them from the analysis results.
+//!
+//! The worst-case usage of each stack is computed from the entry points of the code that runs on
+//! it: the main stack is used by the code that runs in thread mode (e.g. `main`) and by all the
+//! exception handlers, which can preempt it and each other; the process stack is used by the
+//! threads. Each preemption also pushes an exception frame onto the stack that was in use.
+
+use core::fmt::Write as _;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail};
+use log::{error, info};
+use xmas_elf::{sections::SectionData, symbol_table::Entry, ElfFile};
+
+use crate::{max_of, units::Human, Max, Units};
+
+/// Configured values of the stack limit registers
+#[derive(Clone, Debug, Default)]
+pub struct StackLimits {
+    /// Value of the Main Stack Pointer Limit register
+    pub msplim: Option<u32>,
+    /// Value of the Process Stack Pointer Limit register
+    pub psplim: Option<u32>,
+    /// Initial value of the process stack pointer; the initial value of the main stack pointer is
+    /// read from the vector table
+    pub psp_top: Option<u32>,
+    /// Write the recommended values of the stack limit registers, as Rust constants, to this file
+    pub generate: Option<PathBuf>,
+    /// Extra stack space, in bytes, left between the worst-case stack usage and the recommended
+    /// stack limits
+    pub margin: u32,
+    /// Entry point of the code that runs on the main stack in thread mode, e.g. `main`; required
+    /// to check or generate `MSPLIM`
+    pub main: Option<String>,
+    /// Exception handlers; they run on the main stack and are assumed to be able to preempt the
+    /// code that runs in thread mode and each other
+    pub handlers: Vec<String>,
+    /// Entry points of the threads that run on the process stack; required to check or generate
+    /// `PSPLIM`
+    pub threads: Vec<String>,
+}
+
+impl StackLimits {
+    /// Returns `true` if there's nothing to check or generate
+    pub fn is_empty(&self) -> bool {
+        self.msplim.is_none() && self.psplim.is_none() && self.generate.is_none()
+    }
+}
+
+/// Stack limit registers written to by a subroutine
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LimitWrites {
+    pub msplim: bool,
+    pub psplim: bool,
+}
+
+/// Parses an address given in hexadecimal (`0x` prefix) or decimal notation
+///
+/// Underscores can be used as digit separators, e.g. `0x2000_0400`
+pub fn parse_address(s: &str) -> Result<u32, String> {
+    let digits = s.replace('_', "");
+    let res = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        u32::from_str_radix(hex, 16)
+    } else {
+        digits.parse()
+    };
+
+    res.map_err(|e| format!("invalid address `{}`: {}", s, e))
+}
+
+// the initial value of the main stack pointer is the first entry of the vector table
+pub(crate) fn initial_msp(elf: &ElfFile) -> Option<u32> {
+    if let Some(sect) = elf.find_section_by_name(".vector_table") {
+        if let Some(word) = sect.raw_data(elf).get(..4) {
+            return Some(u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+        }
+    }
+
+    // `cortex-m-rt` also exposes the value as a symbol
+    match elf.find_section_by_name(".symtab")?.get_data(elf).ok()? {
+        SectionData::SymbolTable32(entries) => entries
+            .iter()
+            .find(|entry| entry.get_name(elf) == Ok("_stack_start"))
+            .map(|entry| entry.value() as u32),
+        _ => None,
+    }
+}
+
+// bytes the hardware pushes onto the stack on exception entry: the basic frame (8 words) or, if the
+// FPU is enabled, the extended frame (26 words), plus the padding word it may insert to keep the
+// stack 8-byte aligned
+fn exception_frame(fpu: bool) -> u64 {
+    (if fpu { 104 } else { 32 }) + 4
+}
+
+// worst-case usage of the main stack: in the worst case every handler preempts the previous one,
+// with the first one preempting `main`
+pub(crate) fn main_stack(main: Max, handlers: &[Max], fpu: bool) -> Max {
+    handlers.iter().fold(main, |worst, handler| {
+        worst + Max::Exact(exception_frame(fpu)) + *handler
+    })
+}
+
+// worst-case usage of the process stack: the deepest thread plus the frame stacked when an
+// exception preempts it; the handler itself runs on the main stack. Returns `None` if there are
+// no threads
+pub(crate) fn process_stack(threads: &[Max], fpu: bool) -> Option<Max> {
+    max_of(threads.iter().cloned()).map(|worst| worst + Max::Exact(exception_frame(fpu)))
+}
+
+// checks whether `worst` bytes of stack fit between `top` and `limit`; returns `false` if they
+// don't, or if that can't be proven
+pub(crate) fn check(register: &str, limit: u32, top: u32, worst: Max, units: Units) -> bool {
+    if limit > top {
+        error!(
+            "{} ({:#010x}) is above the top of the stack ({:#010x})",
+            register, limit, top
+        );
+        return false;
+    }
+
+    let available = u64::from(top - limit);
+    match worst {
+        Max::Exact(n) if n <= available => {
+            info!(
+                "{} = {:#010x}: worst-case stack usage ({} bytes) fits in the available stack \
+                 ({} bytes)",
+                register,
+                limit,
+                Human(n, units),
+                Human(available, units)
+            );
+
+            return true;
+        }
+
+        Max::LowerBound(n) if n <= available => error!(
+            "{} = {:#010x}: worst-case stack usage is unknown (at least {} bytes); it may not fit \
+             in the available stack ({} bytes)",
+            register,
+            limit,
+            Human(n, units),
+            Human(available, units)
+        ),
+
+        Max::Exact(n) | Max::LowerBound(n) => error!(
+            "{} = {:#010x}: worst-case stack usage ({}{} bytes) does NOT fit in the available \
+             stack ({} bytes)",
+            register,
+            limit,
+            if let Max::LowerBound(_) = worst {
+                "at least "
+            } else {
+                ""
+            },
+            Human(n, units),
+            Human(available, units)
+        ),
+    }
+
+    false
+}
+
+// writes the recommended values of the stack limit registers to `path`; `msp` and `psp` are the
+// top and the worst-case usage of each stack, if known
+pub(crate) fn generate(
+    path: &Path,
+    margin: u32,
+    msp: Option<(u32, Max)>,
+    psp: Option<(u32, Max)>,
+) -> anyhow::Result<()> {
+    let contents = render(margin, msp, psp)?;
+    fs::write(path, contents).map_err(|e| anyhow!("couldn't write `{}`: {}", path.display(), e))
+}
+
+fn render(margin: u32, msp: Option<(u32, Max)>, psp: Option<(u32, Max)>) -> anyhow::Result<String> {
+    if msp.is_none() && psp.is_none() {
+        bail!(
+            "no stack limit to generate; MSPLIM needs the entry point of the main stack and PSPLIM \
+             needs the threads and the top of the process stack"
+        );
+    }
+
+    let mut contents = String::new();
+    writeln!(contents, "// Generated by cargo-call-stack. DO NOT EDIT.")?;
+
+    for (register, stack) in [("MSPLIM", msp), ("PSPLIM", psp)].iter() {
+        let (top, worst) = if let Some(stack) = *stack {
+            stack
+        } else {
+            continue;
+        };
+
+        // a limit computed from a lower bound could fault on legitimate execution
+        let n = match worst {
+            Max::Exact(n) => n,
+            Max::LowerBound(n) => bail!(
+                "the worst-case stack usage of {} is unknown (at least {} bytes); refusing to \
+                 generate a stack limit that may be too low",
+                register,
+                n
+            ),
+        };
+
+        // the 3 least significant bits of the stack limit registers are reserved; round down
+        let limit = u64::from(top)
+            .checked_sub(n + u64::from(margin))
+            .map(|limit| limit & !0b111)
+            .ok_or_else(|| {
+                anyhow!(
+                    "the worst-case stack usage plus margin doesn't fit below the top of the \
+                     stack ({:#010x})",
+                    top
+                )
+            })?;
+
+        writeln!(contents)?;
+        writeln!(
+            contents,
+            "/// Recommended value of the `{}` register",
+            register
+        )?;
+        writeln!(contents, "///")?;
+        writeln!(
+            contents,
+            "/// Worst-case stack usage: {} bytes; margin: {} bytes",
+            n, margin
+        )?;
+        writeln!(
+            contents,
+            "pub const {}: u32 = 0x{:04x}_{:04x};",
+            register,
+            limit >> 16,
+            limit & 0xffff
+        )?;
+    }
+
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn parse_address() {
+        assert_eq!(super::parse_address("0x2000_0400"), Ok(0x2000_0400));
+        assert_eq!(super::parse_address("0X1f"), Ok(0x1f));
+        assert_eq!(super::parse_address("1024"), Ok(1024));
+        assert!(super::parse_address("0x1_0000_0000").is_err());
+        assert!(super::parse_address("sp").is_err());
+    }
+
+    #[test]
+    fn main_stack() {
+        use crate::Max;
+
+        // nothing preempts `main`
+        assert!(super::main_stack(Max::Exact(100), &[], false) == Max::Exact(100));
+        // a handler preempting `main` adds its own usage plus the exception frame
+        assert!(super::main_stack(Max::Exact(100), &[Max::Exact(40)], false) == Max::Exact(176));
+        // nested handlers; the FPU makes the frames larger
+        assert!(
+            super::main_stack(Max::Exact(100), &[Max::Exact(40), Max::Exact(8)], true)
+                == Max::Exact(364)
+        );
+        assert!(
+            super::main_stack(Max::Exact(100), &[Max::LowerBound(40)], false)
+                == Max::LowerBound(176)
+        );
+    }
+
+    #[test]
+    fn process_stack() {
+        use crate::Max;
+
+        assert!(super::process_stack(&[], false).is_none());
+        assert!(
+            super::process_stack(&[Max::Exact(64), Max::Exact(128)], false)
+                == Some(Max::Exact(164))
+        );
+    }
+
+    #[test]
+    fn render() {
+        use crate::Max;
+
+        assert_eq!(
+            super::render(256, Some((0x2001_0000, Max::Exact(1064))), None).unwrap(),
+            "// Generated by cargo-call-stack. DO NOT EDIT.
+
+/// Recommended value of the `MSPLIM` register
+///
+/// Worst-case stack usage: 1064 bytes; margin: 256 bytes
+pub const MSPLIM: u32 = 0x2000_fad8;
+"
+        );
+
+        // each register gets its own worst case
+        assert_eq!(
+            super::render(
+                0,
+                Some((0x2001_0000, Max::Exact(1064))),
+                Some((0x2000_8000, Max::Exact(100)))
+            )
+            .unwrap(),
+            "// Generated by cargo-call-stack. DO NOT EDIT.
+
+/// Recommended value of the `MSPLIM` register
+///
+/// Worst-case stack usage: 1064 bytes; margin: 0 bytes
+pub const MSPLIM: u32 = 0x2000_fbd8;
+
+/// Recommended value of the `PSPLIM` register
+///
+/// Worst-case stack usage: 100 bytes; margin: 0 bytes
+pub const PSPLIM: u32 = 0x2000_7f98;
+"
+        );
+
+        // nothing to generate
+        assert!(super::render(0, None, None).is_err());
+        // not an exact worst case
+        assert!(super::render(0, Some((0x2001_0000, Max::LowerBound(100))), None).is_err());
+        // doesn't fit
+        assert!(super::render(0x100, Some((0x100, Max::Exact(0x100))), None).is_err());
+    }
+}
@@ -0,0 +1,203 @@
+//! Anonymized statistics about the analysis
+//!
+//! Meant to compare the analysis of different projects and to tune their configurations. The
+//! statistics don't include symbol names, file paths or any other information that could identify
+//! the analyzed program; only its target, counters and durations.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::anyhow;
+use petgraph::{algo, graph::Graph, Direction};
+
+use crate::{batch, Local, Node, Timings};
+
+/// Number of edges added to, or removed from, the call graph by each resolution method
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Resolution {
+    /// Calls found in the LLVM-IR
+    pub(crate) llvm_ir: usize,
+    /// Calls found in the machine code
+    pub(crate) machine_code: usize,
+    /// Indirect calls resolved by matching function signatures
+    pub(crate) signatures: usize,
+    /// Indirect calls that may go into untyped symbols (the `?` node)
+    pub(crate) unknown_callees: usize,
+    /// Calls removed by `--unreachable` hints
+    pub(crate) hints: usize,
+}
+
+/// Statistics about a call graph and how it was built
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Stats {
+    target: String,
+    nodes: usize,
+    edges: usize,
+    // strongly connected components that contain a cycle
+    sccs: usize,
+    nodes_without_stack_info: usize,
+    duplicate_symbols: usize,
+    // nodes whose stack usage comes from a hand-written stack model
+    nodes_with_stack_models: usize,
+    // edges whose caller is a real function
+    calls: usize,
+    // edges from a real function to a function pointer / trait object node, or to an unknown
+    // callee (`?`)
+    indirect_calls: usize,
+    resolution: Resolution,
+}
+
+impl Stats {
+    pub(crate) fn new(
+        target: &str,
+        g: &Graph<Node, ()>,
+        duplicate_symbols: usize,
+        nodes_with_stack_models: usize,
+        resolution: Resolution,
+    ) -> Self {
+        let sccs = algo::kosaraju_scc(g)
+            .into_iter()
+            .filter(|scc| {
+                scc.len() > 1
+                    || g.neighbors_directed(scc[0], Direction::Outgoing)
+                        .any(|n| n == scc[0])
+            })
+            .count();
+
+        // function pointer and trait object calls go through dashed (fictitious) nodes; indirect
+        // calls found in the machine code, without type information, go into `?` nodes
+        let calls = g.raw_edges().iter().filter(|edge| !g[edge.source()].dashed);
+        let indirect_calls = calls
+            .clone()
+            .filter(|edge| {
+                let callee = &g[edge.target()];
+                callee.dashed || callee.name == "?"
+            })
+            .count();
+
+        Stats {
+            target: target.to_owned(),
+            nodes: g.node_count(),
+            edges: g.edge_count(),
+            sccs,
+            nodes_without_stack_info: g
+                .node_weights()
+                .filter(|node| node.local == Local::Unknown)
+                .count(),
+            duplicate_symbols,
+            nodes_with_stack_models,
+            calls: calls.count(),
+            indirect_calls,
+            resolution,
+        }
+    }
+
+    /// Writes the statistics, and the `timings`, to `path` in JSON format
+    pub(crate) fn write(&self, path: &Path, timings: &Timings) -> anyhow::Result<()> {
+        let error = |e| anyhow!("couldn't write statistics to `{}`: {}", path.display(), e);
+
+        let mut w = BufWriter::new(File::create(path).map_err(error)?);
+        self.render(&mut w, timings).map_err(error)?;
+        w.flush().map_err(error)
+    }
+
+    fn render(&self, w: &mut impl Write, timings: &Timings) -> io::Result<()> {
+        let indirect_percent = if self.calls == 0 {
+            0.
+        } else {
+            100. * self.indirect_calls as f64 / self.calls as f64
+        };
+
+        write!(w, "{{\"target\":")?;
+        batch::write_str(w, &self.target)?;
+        write!(
+            w,
+            ",\"nodes\":{},\"edges\":{},\"sccs\":{},\"nodes_without_stack_info\":{},\
+             \"duplicate_symbols\":{},\"nodes_with_stack_models\":{},\"calls\":{},\
+             \"indirect_calls\":{},\"indirect_calls_percent\":{:.1}",
+            self.nodes,
+            self.edges,
+            self.sccs,
+            self.nodes_without_stack_info,
+            self.duplicate_symbols,
+            self.nodes_with_stack_models,
+            self.calls,
+            self.indirect_calls,
+            indirect_percent
+        )?;
+
+        let Resolution {
+            llvm_ir,
+            machine_code,
+            signatures,
+            unknown_callees,
+            hints,
+        } = self.resolution;
+        write!(
+            w,
+            ",\"resolution\":{{\"llvm_ir\":{},\"machine_code\":{},\"signatures\":{},\
+             \"unknown_callees\":{},\"unreachable_hints\":{}}}",
+            llvm_ir, machine_code, signatures, unknown_callees, hints
+        )?;
+
+        write!(w, ",\"timings\":")?;
+        timings.json(w)?;
+        writeln!(w, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use petgraph::graph::DiGraph;
+
+    use super::{Resolution, Stats};
+    use crate::{Node, Timings};
+
+    #[test]
+    fn render() {
+        let mut g = DiGraph::new();
+        let main = g.add_node(Node("main", Some(8), false));
+        let foo = g.add_node(Node("foo", Some(16), false));
+        let bar = g.add_node(Node("bar", None, false));
+        let fn_ptr = g.add_node(Node("void ()*", Some(0), true));
+        g.add_edge(main, foo, ());
+        g.add_edge(foo, foo, ());
+        g.add_edge(main, bar, ());
+        g.add_edge(main, fn_ptr, ());
+        g.add_edge(fn_ptr, foo, ());
+        g.add_edge(fn_ptr, bar, ());
+        // untyped indirect call
+        let unknown = g.add_node(Node("?", None, false));
+        g.add_edge(bar, unknown, ());
+
+        let resolution = Resolution {
+            llvm_ir: 3,
+            machine_code: 1,
+            signatures: 2,
+            unknown_callees: 1,
+            hints: 1,
+        };
+        let stats = Stats::new("thumbv7m-none-eabi", &g, 0, 1, resolution);
+
+        let mut timings = Timings::new();
+        timings.record("solve", Duration::from_micros(42));
+
+        let mut out = vec![];
+        stats.render(&mut out, &timings).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"target\":\"thumbv7m-none-eabi\",\"nodes\":5,\"edges\":7,\"sccs\":1,\
+             \"nodes_without_stack_info\":2,\"duplicate_symbols\":0,\
+             \"nodes_with_stack_models\":1,\"calls\":5,\"indirect_calls\":2,\
+             \"indirect_calls_percent\":40.0,\"resolution\":{\"llvm_ir\":3,\"machine_code\":1,\
+             \"signatures\":2,\"unknown_callees\":1,\"unreachable_hints\":1},\
+             \"timings\":{\"solve\":42}}\n"
+        );
+    }
+}
@@ -1,14 +1,21 @@
+use crate::splim::LimitWrites;
+
 /// Analyzes a subroutine and returns all the `BL` and `B` instructions in it, plus whether this
 /// function performs an indirect function call or not
+///
+/// `v7` must be set for ARMv7-M and ARMv8-M Mainline (Thumb-2) code; `v8` must be set for ARMv8-M
+/// (Baseline and Mainline) code
 // NOTE we assume that `bytes` is always valid input so all errors are bugs
 // Reference: ARMv7-M Architecture Reference Manual (ARM DDI 0403E.b)
 // Reference: ARMv6-M Architecture Reference Manual (ARM DDI 0419D)
+// Reference: ARMv8-M Architecture Reference Manual (ARM DDI 0553)
 pub fn analyze(
     bytes: &[u8],
     address: u32,
     v7: bool,
+    v8: bool,
     tags: &[(u32, Tag)],
-) -> (Vec<i32>, Vec<i32>, bool, bool, Option<u64>) {
+) -> (Vec<i32>, Vec<i32>, bool, bool, Option<u64>, LimitWrites) {
     macro_rules! bug {
         ($first:expr) => {
             panic!(
@@ -49,6 +56,8 @@ pub fn analyze(
     let mut bls = vec![];
     let mut bs = vec![];
     let mut indirect = false;
+    // ARMv8-M only: writes to the stack limit registers
+    let mut limit_writes = LimitWrites::default();
     let mut halfwords = bytes.chunks_exact(2).zip(0i32..);
     while let Some((first, i)) = halfwords.next() {
         let start = address + 2 * i as u32;
@@ -182,7 +191,18 @@ pub fn analyze(
             if rm != 0b1110 {
                 indirect = true;
             }
-        } else if v7 && matches(first, "0b1011_x_0_x_1_xxxxx_xxx") {
+        } else if v8 && matches(first, "0b010001_11_1_xxxx_100") {
+            // (ARMv8-M) BLXNS - T1
+            indirect = true;
+        } else if v8 && matches(first, "0b010001_11_0_xxxx_100") {
+            // (ARMv8-M) BXNS - T1
+            let rm = (first[0] >> 3) & 0b1111;
+
+            // `bxns lr` is a return to Non-secure state
+            if rm != 0b1110 {
+                indirect = true;
+            }
+        } else if (v7 || v8) && matches(first, "0b1011_x_0_x_1_xxxxx_xxx") {
             // A7.7.21  CBNZ, CBZ - T1
             continue;
         } else if matches(first, "0b010000_1011_xxx_xxx") {
@@ -450,6 +470,22 @@ pub fn analyze(
                 if let Some(stack) = stack.as_mut() {
                     *stack += u64::from(imm32);
                 }
+            } else if v8
+                && matches(first, "0b11110_0_1110_0_x_xxxx")
+                && matches(second, "0b10_x_0_xxxx_xxxxxxxx")
+            {
+                // MSR (register) - T1
+                // we want to know if the stack limit registers are written to
+                const MSPLIM: u8 = 0b0000_1010;
+                const PSPLIM: u8 = 0b0000_1011;
+
+                // the MSB selects the Non-secure alias of the register
+                let sysm = second[0] & 0b0111_1111;
+                if sysm == MSPLIM {
+                    limit_writes.msplim = true;
+                } else if sysm == PSPLIM {
+                    limit_writes.psplim = true;
+                }
             } else if v7
                 && matches(first, "0b11110_x_xxxxxxxxxx")
                 && matches(second, "0b10_x_0_x_xxxxxxxxxxx")
@@ -485,7 +521,7 @@ pub fn analyze(
                 }
 
                 bs.push(imm32);
-            } else if v7
+            } else if (v7 || v8)
                 && matches(first, "0b11110_x_xxxxxxxxxx")
                 && matches(second, "0b10_x_1_x_xxxxxxxxxxx")
             {
@@ -568,7 +604,7 @@ pub fn analyze(
         }
     }
 
-    (bls, bs, indirect, modifies_sp, stack)
+    (bls, bs, indirect, modifies_sp, stack, limit_writes)
 }
 
 fn matches(bytes: &[u8], pattern: &str) -> bool {
@@ -631,65 +667,151 @@ pub enum Tag {
 
 #[cfg(test)]
 mod tests {
+    use super::LimitWrites;
+
     #[test]
     fn sanity() {
         assert_eq!(
-            super::analyze(&[0xff, 0xf7, 0xe4, 0xfe], 0, false, &[]).0,
+            super::analyze(&[0xff, 0xf7, 0xe4, 0xfe], 0, false, false, &[]).0,
             vec![-568 + 4]
         );
 
         assert_eq!(
-            super::analyze(&[0x00, 0xf0, 0x2a, 0xfa], 0, false, &[]).0,
+            super::analyze(&[0x00, 0xf0, 0x2a, 0xfa], 0, false, false, &[]).0,
             vec![1108 + 4]
         );
 
         assert_eq!(
-            super::analyze(&[0x03, 0xe2], 0, false, &[]).1,
+            super::analyze(&[0x03, 0xe2], 0, false, false, &[]).1,
             vec![1030 + 4]
         );
 
         // UDF
         assert_eq!(
-            super::analyze(&[0xfe, 0xde], 0, true, &[]),
-            (vec![], vec![], false, false, Some(0))
+            super::analyze(&[0xfe, 0xde], 0, true, false, &[]),
+            (
+                vec![],
+                vec![],
+                false,
+                false,
+                Some(0),
+                LimitWrites::default()
+            )
         );
     }
 
     #[test]
     fn modifies_sp() {
         // bf00            nop
-        let nop = super::analyze(&[0x00, 0xbf], 0, false, &[]);
+        let nop = super::analyze(&[0x00, 0xbf], 0, false, false, &[]);
         assert!(!nop.3);
         assert_eq!(nop.4, Some(0));
 
         // b081            sub     sp, #4
-        let sub = super::analyze(&[0x81, 0xb0], 0, false, &[]);
+        let sub = super::analyze(&[0x81, 0xb0], 0, false, false, &[]);
         assert!(sub.3);
         assert_eq!(sub.4, Some(4));
 
         // b580            push    {r7, lr}
-        let push = super::analyze(&[0x80, 0xb5], 0, false, &[]);
+        let push = super::analyze(&[0x80, 0xb5], 0, false, false, &[]);
         assert!(push.3);
         assert_eq!(push.4, Some(8));
 
         // e92d 41f0       stmdb   sp!, {r4, r5, r6, r7, r8, lr}
-        let stmdb = super::analyze(&[0x2d, 0xe9, 0xf0, 0x41], 0, true, &[]);
+        let stmdb = super::analyze(&[0x2d, 0xe9, 0xf0, 0x41], 0, true, false, &[]);
         assert!(stmdb.3);
         assert_eq!(stmdb.4, Some(24));
 
         // ed2d 8b02       vpush   {d8}
-        let vpush = super::analyze(&[0x2d, 0xed, 0x02, 0x8b], 0, true, &[]);
+        let vpush = super::analyze(&[0x2d, 0xed, 0x02, 0x8b], 0, true, false, &[]);
         assert!(vpush.3);
         assert_eq!(vpush.4, Some(8));
 
         // f5ad 7d02       sub.w   sp, sp, #520    ; 0x208
-        let subw = super::analyze(&[0xad, 0xf5, 0x02, 0x7d], 0, true, &[]);
+        let subw = super::analyze(&[0xad, 0xf5, 0x02, 0x7d], 0, true, false, &[]);
         assert!(subw.3);
         assert_eq!(subw.4, Some(520));
 
         // f84d bd04       str     r11, [sp, #-4]!
-        let str = super::analyze(&[0x4d, 0xf8, 0x04, 0xbd], 0, true, &[]);
+        let str = super::analyze(&[0x4d, 0xf8, 0x04, 0xbd], 0, true, false, &[]);
         assert!(str.3);
         assert_eq!(str.4, Some(4));
     }
+
+    #[test]
+    fn v8m() {
+        // 478c            blxns   r1
+        let blxns = super::analyze(&[0x8c, 0x47], 0, false, true, &[]);
+        assert!(blxns.2);
+
+        // 4774            bxns    lr
+        let bxns = super::analyze(&[0x74, 0x47], 0, false, true, &[]);
+        assert!(!bxns.2);
+
+        // b100            cbz     r0, 0x4
+        let cbz = super::analyze(&[0x00, 0xb1], 0, false, true, &[]);
+        assert_eq!(cbz.4, Some(0));
+
+        // f000 b800       b.w     0x4
+        assert_eq!(
+            super::analyze(&[0x00, 0xf0, 0x00, 0xb8], 0, false, true, &[]).1,
+            vec![4]
+        );
+
+        // f380 880a       msr     MSPLIM, r0
+        let msplim = super::analyze(&[0x80, 0xf3, 0x0a, 0x88], 0, true, true, &[]);
+        assert_eq!(
+            msplim.5,
+            LimitWrites {
+                msplim: true,
+                psplim: false
+            }
+        );
+
+        // f381 888b       msr     PSPLIM_NS, r1
+        let psplim = super::analyze(&[0x81, 0xf3, 0x8b, 0x88], 0, false, true, &[]);
+        assert_eq!(
+            psplim.5,
+            LimitWrites {
+                msplim: false,
+                psplim: true
+            }
+        );
+
+        // ec20 0a00       vlstm   r0
+        // saves the floating point context at the address in r0 but doesn't write back r0
+        let vlstm = super::analyze(&[0x20, 0xec, 0x00, 0x0a], 0, true, true, &[]);
+        assert!(!vlstm.3);
+        assert_eq!(vlstm.4, Some(0));
+
+        // ec20 0a00       vlstm   r0
+        // b081            sub     sp, #4
+        // a 32-bit instruction: the next one is decoded from the right offset
+        let vlstm_sub = super::analyze(&[0x20, 0xec, 0x00, 0x0a, 0x81, 0xb0], 0, true, true, &[]);
+        assert!(vlstm_sub.3);
+        assert_eq!(vlstm_sub.4, Some(4));
+
+        // e97f e97f       sg
+        let sg = super::analyze(&[0x7f, 0xe9, 0x7f, 0xe9], 0, true, true, &[]);
+        assert_eq!(
+            sg,
+            (
+                vec![],
+                vec![],
+                false,
+                false,
+                Some(0),
+                LimitWrites::default()
+            )
+        );
+
+        // e97f e97f       sg
+        // b580            push    {r7, lr}
+        // decoding SG as two 16-bit halves would make its second half swallow the PUSH
+        for v7 in [false, true].iter() {
+            let sg_push = super::analyze(&[0x7f, 0xe9, 0x7f, 0xe9, 0x80, 0xb5], 0, *v7, true, &[]);
+            assert!(sg_push.3);
+            assert_eq!(sg_push.4, Some(8));
+        }
+    }
 }
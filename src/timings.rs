@@ -0,0 +1,136 @@
+//! Durations of the phases of the analysis
+//!
+//! Useful to report performance issues and to track performance regressions
+
+use std::{
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+/// Durations of the phases of the analysis, in the order they were first recorded
+#[derive(Clone, Debug, Default)]
+pub struct Timings {
+    phases: Vec<(&'static str, Duration)>,
+    // start of the current phase
+    lap_start: Option<Instant>,
+}
+
+impl Timings {
+    /// Creates an empty set of timings
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `duration` to the time spent in `phase`
+    pub fn record(&mut self, phase: &'static str, duration: Duration) -> &mut Self {
+        if let Some((_, total)) = self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            *total += duration;
+        } else {
+            self.phases.push((phase, duration));
+        }
+
+        self
+    }
+
+    // starts timing a new phase
+    pub(crate) fn start(&mut self) {
+        self.lap_start = Some(Instant::now());
+    }
+
+    // adds the time elapsed since the start of the current phase to the time spent in `phase` and
+    // starts timing a new phase
+    pub(crate) fn lap(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        if let Some(start) = self.lap_start {
+            self.record(phase, now - start);
+        }
+        self.lap_start = Some(now);
+    }
+
+    /// Returns the time spent in `phase`, if it was recorded
+    pub fn get(&self, phase: &str) -> Option<Duration> {
+        self.phases
+            .iter()
+            .find(|(name, _)| *name == phase)
+            .map(|(_, duration)| *duration)
+    }
+
+    /// Returns the time spent in all the recorded phases
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|(_, duration)| *duration).sum()
+    }
+
+    // `   ir parse 1.234s` lines
+    pub(crate) fn print(&self, w: &mut impl Write) -> io::Result<()> {
+        let width = self
+            .phases
+            .iter()
+            .map(|(name, _)| name.len())
+            .max()
+            .unwrap_or(0)
+            .max("total".len());
+
+        for (name, duration) in &self.phases {
+            writeln!(w, "{:>2$} {:.3}s", name, duration.as_secs_f64(), width)?;
+        }
+        writeln!(
+            w,
+            "{:>2$} {:.3}s",
+            "total",
+            self.total().as_secs_f64(),
+            width
+        )
+    }
+
+    // `{"cargo build":1234,...}`; durations in microseconds
+    pub(crate) fn json(&self, w: &mut impl Write) -> io::Result<()> {
+        write!(w, "{{")?;
+        for (i, (name, duration)) in self.phases.iter().enumerate() {
+            if i != 0 {
+                write!(w, ",")?;
+            }
+
+            write!(w, "\"{}\":{}", name, duration.as_micros())?;
+        }
+        write!(w, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Timings;
+
+    #[test]
+    fn record() {
+        let mut timings = Timings::new();
+        timings
+            .record("cargo build", Duration::from_millis(1500))
+            .record("resolution", Duration::from_millis(20))
+            .record("disassembly", Duration::from_micros(250))
+            .record("resolution", Duration::from_millis(5));
+
+        assert_eq!(timings.get("resolution"), Some(Duration::from_millis(25)));
+        assert_eq!(timings.get("solve"), None);
+        assert_eq!(timings.total(), Duration::from_micros(1_525_250));
+
+        let mut out = vec![];
+        timings.print(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "cargo build 1.500s
+ resolution 0.025s
+disassembly 0.000s
+      total 1.525s
+"
+        );
+
+        let mut out = vec![];
+        timings.json(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"cargo build\":1500000,\"resolution\":25000,\"disassembly\":250}"
+        );
+    }
+}
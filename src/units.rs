@@ -0,0 +1,125 @@
+//! Rendering of byte figures in human-facing output formats
+//!
+//! Machine-facing formats always use raw integers; the helpers in this module are only meant for
+//! output that's read by people (e.g. the labels of the dot graph). Formatting doesn't depend on
+//! the system locale: the thousands separator is always `,` and the decimal mark is always `.`
+
+use core::{
+    fmt::{self, Write as _},
+    str,
+};
+
+use crate::{Local, Max};
+
+/// Units used to render byte figures
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum Units {
+    /// Use KiB for figures of 1 KiB or more and bytes for smaller figures
+    Auto,
+    /// Always use bytes
+    #[default]
+    Bytes,
+}
+
+/// Renders `T` using the given `Units`
+pub(crate) struct Human<T>(pub T, pub Units);
+
+impl fmt::Display for Human<u64> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const KIB: u64 = 1024;
+
+        let Human(n, units) = *self;
+        match units {
+            Units::Auto if n >= KIB => {
+                if n % KIB == 0 {
+                    grouped(f, n / KIB)?;
+                } else {
+                    // round to the nearest hundredth
+                    let hundredths = (u128::from(n) * 100 + u128::from(KIB) / 2) / u128::from(KIB);
+                    grouped(f, (hundredths / 100) as u64)?;
+                    write!(f, ".{:02}", hundredths % 100)?;
+                }
+
+                f.write_str(" KiB")
+            }
+
+            Units::Auto => write!(f, "{} B", n),
+
+            Units::Bytes => grouped(f, n),
+        }
+    }
+}
+
+impl fmt::Display for Human<Local> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Local::Exact(n) => Human(n, self.1).fmt(f),
+            Local::Unknown => f.write_str("?"),
+        }
+    }
+}
+
+impl fmt::Display for Human<Max> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Max::Exact(n) => write!(f, "= {}", Human(n, self.1)),
+            Max::LowerBound(n) => write!(f, ">= {}", Human(n, self.1)),
+        }
+    }
+}
+
+// writes `n` using `,` as the thousands separator
+fn grouped(f: &mut fmt::Formatter, n: u64) -> fmt::Result {
+    let digits = n.to_string();
+
+    // the leading group may have fewer than 3 digits
+    let (head, tail) = digits.split_at(match digits.len() % 3 {
+        0 => 3,
+        len => len,
+    });
+
+    f.write_str(head)?;
+    for group in tail.as_bytes().chunks(3) {
+        f.write_char(',')?;
+        f.write_str(str::from_utf8(group).expect("UNREACHABLE"))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Human, Units};
+    use crate::{Local, Max};
+
+    #[test]
+    fn bytes() {
+        assert_eq!(Human(0, Units::Bytes).to_string(), "0");
+        assert_eq!(Human(999, Units::Bytes).to_string(), "999");
+        assert_eq!(Human(1839, Units::Bytes).to_string(), "1,839");
+        assert_eq!(Human(18392, Units::Bytes).to_string(), "18,392");
+        assert_eq!(Human(1234567, Units::Bytes).to_string(), "1,234,567");
+    }
+
+    #[test]
+    fn auto() {
+        assert_eq!(Human(520, Units::Auto).to_string(), "520 B");
+        assert_eq!(Human(1024, Units::Auto).to_string(), "1 KiB");
+        assert_eq!(Human(1839, Units::Auto).to_string(), "1.80 KiB");
+        assert_eq!(Human(18392, Units::Auto).to_string(), "17.96 KiB");
+        assert_eq!(Human(2047, Units::Auto).to_string(), "2.00 KiB");
+        assert_eq!(Human(2 * 1024 * 1024, Units::Auto).to_string(), "2,048 KiB");
+    }
+
+    #[test]
+    fn stack() {
+        assert_eq!(Human(Local::Unknown, Units::Auto).to_string(), "?");
+        assert_eq!(Human(Local::Exact(4096), Units::Bytes).to_string(), "4,096");
+        assert_eq!(Human(Max::Exact(8), Units::Auto).to_string(), "= 8 B");
+        assert_eq!(
+            Human(Max::LowerBound(2048), Units::Bytes).to_string(),
+            ">= 2,048"
+        );
+    }
+}
@@ -5,9 +5,16 @@ use rustc_version::Channel;
 const ALL_TARGETS: &[&str] = &[
     "thumbv6m-none-eabi",
     "thumbv7m-none-eabi",
+    "thumbv8m.base-none-eabi",
+    "thumbv8m.main-none-eabi",
     "aarch64-unknown-none",
 ];
-const FMUL_TARGETS: &[&str] = &["thumbv6m-none-eabi", "thumbv7m-none-eabi"];
+const FMUL_TARGETS: &[&str] = &[
+    "thumbv6m-none-eabi",
+    "thumbv7m-none-eabi",
+    "thumbv8m.base-none-eabi",
+    "thumbv8m.main-none-eabi",
+];
 
 fn for_all_targets(mut f: impl FnMut(&str)) {
     for target in ALL_TARGETS {